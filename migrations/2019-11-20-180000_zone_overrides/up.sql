@@ -0,0 +1,8 @@
+CREATE TABLE zone_overrides (
+  id VARCHAR(255) NOT NULL PRIMARY KEY,
+  name VARCHAR(255) NOT NULL,
+  temperature REAL NOT NULL,
+  expires VARCHAR(255) NOT NULL,
+  dtc VARCHAR(255) NOT NULL
+);
+CREATE INDEX zone_overrides_name_index ON zone_overrides (name);
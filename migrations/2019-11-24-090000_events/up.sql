@@ -0,0 +1,8 @@
+CREATE TABLE events (
+  id VARCHAR(255) NOT NULL PRIMARY KEY,
+  name VARCHAR(255) NOT NULL,
+  kind VARCHAR(255) NOT NULL,
+  message TEXT NOT NULL,
+  dtc VARCHAR(255) NOT NULL
+);
+CREATE INDEX events_name_kind_index ON events (name, kind);
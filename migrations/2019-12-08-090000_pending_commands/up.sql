@@ -0,0 +1,12 @@
+CREATE TABLE pending_commands (
+  id VARCHAR(255) NOT NULL PRIMARY KEY,
+  name VARCHAR(255) NOT NULL,
+  pin INTEGER(1) NOT NULL,
+  value INTEGER(2) NOT NULL,
+  attempts INTEGER(2) NOT NULL DEFAULT 1,
+  next_retry VARCHAR(255) NOT NULL,
+  dtc VARCHAR(255) NOT NULL,
+  last_sent VARCHAR(255) NOT NULL,
+  failed BOOLEAN NOT NULL DEFAULT 0
+);
+CREATE INDEX pending_commands_name_pin_index ON pending_commands (name, pin);
@@ -0,0 +1,8 @@
+CREATE TABLE pid_states (
+  id VARCHAR(255) NOT NULL PRIMARY KEY,
+  name VARCHAR(255) NOT NULL,
+  integral REAL NOT NULL,
+  last_error REAL NOT NULL,
+  last_update VARCHAR(255) NOT NULL
+);
+CREATE INDEX pid_states_name_index ON pid_states (name);
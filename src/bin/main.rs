@@ -11,12 +11,10 @@ extern crate speculate;
 
 use mosquitto_client::{Mosquitto};
 use std::io::{Error, ErrorKind};
-use std::time::Duration;
-use std::thread;
 use clap::{App, load_yaml};
 use env_logger::Env;
 use log::{warn,info};
-use chrono::{Local};
+use chrono::{DateTime, Local};
 
 #[path = "../config.rs"]
 pub mod config;
@@ -29,22 +27,29 @@ pub mod zone;
 pub mod repository;
 #[path = "../deciders.rs"]
 pub mod deciders;
+#[path = "../strategies.rs"]
+pub mod strategies;
 #[path = "../state_retriever.rs"]
 pub mod state_retriever;
 #[path = "../schema.rs"]
 pub mod schema;
+#[path = "../clock.rs"]
+pub mod clock;
 
-use crate::config::{load_config, has_config_changed, Settings};
-use crate::helper::{print_info, send_to_zone};
-use crate::deciders::{ZoneStateDecider, TemperatureStateDecider, HeaterDecider};
-use crate::state_retriever::{StateRetriever, PinChanges};
+use crate::config::{load_config, has_config_changed, Settings, ControlNodes};
+use crate::helper::{print_info, connect_client, send_to_zone, override_from_message, rom_temperature_from_message, is_relevant_trigger, save_override_command, Trigger};
+use crate::deciders::{ZoneStateDecider, TemperatureStateDecider, HeaterDecider, WindowOpenDecider, StaleSensorDecider, CycleDecider, ExerciseDecider, HotWaterDecider, PumpDecider, CommandDecider};
+use crate::state_retriever::{StateRetriever, Deciders, PinChanges};
 use crate::repository::{PinStateRepository};
-use arduino_mqtt_pin::pin::{PinOperation};
-use std::sync::{Arc};
+use crate::clock::{Clock, SystemClock, AcceleratedClock};
+use arduino_mqtt_pin::pin::{PinOperation, PinState, PinValue};
+use std::sync::{Arc, Mutex};
 use diesel::{SqliteConnection, Connection};
 
 embed_migrations!("migrations");
 
+const MIN_EVALUATION_GAP_MS: i64 = 1000;
+
 
 fn main() -> Result<(), Error>
 {
@@ -53,6 +58,7 @@ fn main() -> Result<(), Error>
     let verbosity: u8 = matches.occurrences_of("verbose") as u8;
     let config_path = matches.value_of("config").unwrap_or("config.conf");
     let db_path = matches.value_of("db").unwrap_or("pins.sqlite3");
+    let speed: u32 = matches.value_of("speed").and_then(|v| v.parse().ok()).unwrap_or(1);
 
     env_logger::from_env(Env::default().default_filter_or(match verbosity { 1 => "debug", 2 => "trace", _ => "info"})).init();
 
@@ -63,24 +69,47 @@ fn main() -> Result<(), Error>
 
     info!("Using config path: {}", config_path);
 
+    let clock: Box<dyn Clock> = if speed > 1 {
+        info!("Clock runs {} times faster", speed);
+        Box::new(AcceleratedClock::new(Local::now(), speed))
+    } else {
+        Box::new(SystemClock)
+    };
+
     let (conf_temp, mut control_nodes) = load_config(config_path, verbosity)?;
     let config = Settings::new(conf_temp);
 
-    let repository = Arc::new(PinStateRepository::new(&connection));
-    let temperature_decider = TemperatureStateDecider::new(&config);
-    let zone_decider = ZoneStateDecider::new(&temperature_decider, &config);
+    let repository = Arc::new(PinStateRepository::new(&connection).with_config(&config));
+    let temperature_decider = TemperatureStateDecider::new(&repository, &config);
+    let zone_decider = ZoneStateDecider::new(&repository, &temperature_decider, &config);
     let heater_decider = HeaterDecider::new(&repository, &config);
-    let state_retriever = StateRetriever::new(&repository, &heater_decider, &zone_decider, &config);
+    let window_decider = WindowOpenDecider::new(&repository, &config);
+    let stale_decider = StaleSensorDecider::new(&repository, &config);
+    let cycle_decider = CycleDecider::new(&repository, &config);
+    let exercise_decider = ExerciseDecider::new(&repository, &cycle_decider, &config);
+    let hot_water_decider = HotWaterDecider::new(&repository, &config);
+    let pump_decider = PumpDecider::new(&repository, &config);
+    let command_decider = CommandDecider::new(&repository, &config);
+    let state_retriever = StateRetriever::new(&repository, Deciders {
+        heater: &heater_decider,
+        zone: &zone_decider,
+        window: &window_decider,
+        stale: &stale_decider,
+        cycle: &cycle_decider,
+        exercise: &exercise_decider,
+        hot_water: &hot_water_decider,
+        pump: &pump_decider
+    }, &config);
+    warn_unreported_sensors(&stale_decider, &control_nodes);
 
-    let client = Mosquitto::new(&format!("{}-main", config.name()));
-    client.connect(&config.host(), 1883)
-        .map_err(|e| Error::new(ErrorKind::NotConnected, format!("Unable to connect to host: {} {}", config.host(), e)))?;
+    let client = connect_client(&config, "main")?;
 
     /*
      * receive remote on :
      * prefix/nodes/some-node-id/analog/set/3 1
      * receive local on:
      * prefix/master/analog/timeout/3
+     * prefix/master/override/zone-name {"temperature": 23.0, "duration": 120}
      */
     let remote_set = format!("{}/nodes/+/current/#", config.name());
     let local_set = format!("{}/master/#", config.name());
@@ -93,12 +122,43 @@ fn main() -> Result<(), Error>
         .map_err(|e| Error::new(ErrorKind::NotConnected, format!("Unable to subscribe: {} {:?}", local_set, e)))?;
 
 
+    // messages are handled within do_loop, the loop decides which of them need a new evaluation
+    let triggers: Arc<Mutex<Vec<Trigger>>> = Arc::new(Mutex::new(Vec::new()));
     let mut m = client.callbacks(());
     let mrepository = Arc::clone(&repository);
+    let mtriggers = Arc::clone(&triggers);
+    let mclock = &clock;
+    let simulated = speed > 1;
+    let namespace = config.name();
     m.on_message(move |_,msg| {
+        let add_trigger = |trigger: Trigger| if let Ok(mut arr) = mtriggers.lock() {
+            arr.push(trigger);
+        };
+
+        // overrides are saved in the loop where the configured zones are known
+        if let Some(command) = override_from_message(&namespace, msg.topic(), msg.text()) {
+            add_trigger(Trigger::Override(command));
+            return;
+        }
+
+        if let Some((sensor_name, temperature)) = rom_temperature_from_message(&namespace, msg.topic(), msg.text()) {
+            mrepository.save_state(&PinOperation::new(
+                PinState::new(0, PinValue::Temperature(temperature), mclock.now(), None),
+                sensor_name.clone()
+            ));
+            add_trigger(Trigger::Reading(sensor_name, 0));
+            return;
+        }
 
         match PinOperation::from_message(&msg) {
-            Ok(o) => mrepository.save_state(&o),
+            Ok(mut o) => {
+                // node timestamps are kept unless readings have to follow the simulated clock
+                if simulated {
+                    o.pin_state.dt = mclock.now();
+                }
+                mrepository.save_state(&o);
+                add_trigger(Trigger::Reading(o.node.clone(), o.pin_state.pin));
+            },
             Err(e) => {
                 warn!("Failed to parse message {:?}", msg);
                 warn!("{}", e);
@@ -106,34 +166,91 @@ fn main() -> Result<(), Error>
         }
     });
 
+    let mut last_evaluation: Option<DateTime<Local>> = None;
+    let mut pending = true;
     loop {
         if has_config_changed(config_path, config.version()) {
             let (new_config, nodes) = load_config(config_path, verbosity)?;
             control_nodes = nodes;
             config.replace(new_config);
+            warn_unreported_sensors(&stale_decider, &control_nodes);
+            pending = true;
         }
 
-        let controls: PinChanges = state_retriever.get_pins_expected_to_change(&control_nodes, &Local::now());
+        let now = clock.now();
+        let received: Vec<Trigger> = triggers.lock().map(|mut arr| arr.drain(..).collect()).unwrap_or(vec![]);
+        for trigger in &received {
+            let relevant = match trigger {
+                Trigger::Override(command) => save_override_command(&repository, &control_nodes, command, &now),
+                _ => is_relevant_trigger(trigger, &config, &control_nodes)
+            };
+            pending = pending || relevant;
+        }
+
+        // bursts of readings are evaluated together, the interval is a safety net
+        let since_last = last_evaluation.map(|dt| now - dt);
+        let due = since_last.map_or(true, |elapsed| elapsed >= chrono::Duration::seconds(config.control_interval() as i64));
+        let settled = since_last.map_or(true, |elapsed| elapsed >= chrono::Duration::milliseconds(MIN_EVALUATION_GAP_MS));
+        if !(due || (pending && settled)) {
+            poll_mqtt(&client)?;
+            continue;
+        }
+        pending = false;
+        last_evaluation = Some(now);
+
+        let controls: PinChanges = state_retriever.get_pins_expected_to_change(&control_nodes, &now);
         if controls.len() > 0 {
             info!("States expected to change: {}", controls.iter().map(|(n, m)| m.len()).sum::<usize>());
         }
 
+        // changes already sent are waiting for acknowledgement and resent only when due
+        for command in command_decider.get_commands_to_retry(&now) {
+            let (pin, value) = match command.get_pin_value() {
+                Some(pin_value) => pin_value,
+                None => continue
+            };
+            let replaced = controls.get(&command.name)
+                .and_then(|pins| pins.get(&pin))
+                .map_or(false, |expected| expected.as_u16() != value);
+            if replaced {
+                continue;
+            }
+            info!("Retrying {} pin {} value {} attempt {}", command.name, pin, value, command.attempts + 1);
+            send_to_zone(&client, pin, value, &config.name(), &command.name);
+            command_decider.save_sent(&command.name, pin, value, &now);
+        }
+
         for (control_name, pins) in &controls {
             for (pin, value) in pins {
+                if command_decider.is_pending(control_name, *pin, value.as_u16()) {
+                    continue;
+                }
                 send_to_zone(&client, *pin, value.as_u16(), &config.name(), control_name);
+                command_decider.save_sent(control_name, *pin, value.as_u16(), &now);
             }
         }
 
-        print_info(&repository, &control_nodes);
+        print_info(&repository, &config, &control_nodes, &now);
 
-        for i in 0..20 {
-            let conn_result = client.do_loop(-1)
-                .map_err(|e| Error::new(ErrorKind::NotConnected, format!("Mqtt error {}", e)));
-            if !conn_result.is_ok() {
-                client.reconnect()
-                    .map_err(|e| Error::new(ErrorKind::NotConnected, format!("Mqtt can not reconnect {}", e)))?;
-            }
-            thread::sleep(Duration::from_millis(500));
-        }
+        poll_mqtt(&client)?;
+    }
+}
+
+// waits up to a second for messages
+fn poll_mqtt(client: &Mosquitto) -> Result<(), Error>
+{
+    let conn_result = client.do_loop(-1)
+        .map_err(|e| Error::new(ErrorKind::NotConnected, format!("Mqtt error {}", e)));
+    if !conn_result.is_ok() {
+        client.reconnect()
+            .map_err(|e| Error::new(ErrorKind::NotConnected, format!("Mqtt can not reconnect {}", e)))?;
+    }
+    Ok(())
+}
+
+fn warn_unreported_sensors(stale_decider: &StaleSensorDecider, control_nodes: &ControlNodes)
+{
+    for zone in stale_decider.get_unreported_zones(control_nodes) {
+        warn!("Zone {} sensor {} pin {} has not reported any temperature, check sensor_node and sensor_address", zone.name, zone.get_sensor_name(), zone.get_sensor_pin());
     }
 }
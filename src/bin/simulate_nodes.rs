@@ -25,6 +25,7 @@ pub mod repository;
 pub mod schema;
 
 use crate::config::{load_config, ControlNodes, Settings};
+use crate::helper::connect_client;
 
 fn send_temperature(client: &Mosquitto, namespace: &str, name: &str, pin: u8, value: f32) -> bool
 {
@@ -87,9 +88,7 @@ fn main() -> Result<(), Error>
     env_logger::from_env(Env::default().default_filter_or("debug")).init();
     let config = Settings::new(config);
 
-    let client = Mosquitto::new("test1");//&format!("{}-simulate", config.name));
-    client.connect(&config.host(), 1883)
-        .map_err(|e| Error::new(ErrorKind::NotConnected, format!("Unable to connect to host: {} {:?}", config.host(), e)))?;
+    let client = connect_client(&config, "simulate")?;
 
 
     for (node_name, control_node) in &control_nodes {
@@ -9,6 +9,10 @@ extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
 
+#[cfg(test)]
+#[macro_use]
+extern crate speculate;
+
 #[path = "../config.rs"]
 pub mod config;
 #[path = "../helper.rs"]
@@ -18,8 +22,14 @@ pub mod helper;
 pub mod zone;
 #[path = "../repository.rs"]
 pub mod repository;
+#[path = "../deciders.rs"]
+pub mod deciders;
+#[path = "../strategies.rs"]
+pub mod strategies;
 #[path = "../schema.rs"]
 pub mod schema;
+#[path = "../clock.rs"]
+pub mod clock;
 
 
 use std::fs::File;
@@ -33,16 +43,20 @@ use diesel::{SqliteConnection, Connection};
 use std::collections::HashMap;
 use arduino_mqtt_pin::pin::PinState;
 use serde::{Serialize, Deserialize};
-use chrono::{Local, Duration};
-use crate::repository::{PinStateRepository};
+use chrono::{DateTime, Local, Duration, NaiveDate, TimeZone};
+use crate::repository::{PinStateRepository, EVENT_EXERCISE};
+use crate::deciders::{TemperatureStateDecider, HeaterDecider, WindowOpenDecider, StaleSensorDecider, HotWaterDecider, get_outdoor_temperature, get_heater_state, get_pump_pin, is_hot_water_charging};
 use derive_new::new;
+use crate::clock::{Clock, SystemClock};
+use crate::helper::{OverrideCommand, connect_client, send_override};
 
 #[derive(new)]
 struct UiSettings
 {
     pub config_path: String,
     pub html_path: String,
-    pub db_path: String
+    pub db_path: String,
+    pub clock: Box<dyn Clock + Send + Sync>
 }
 
 #[derive(Serialize, Deserialize, Debug, new, PartialEq)]
@@ -87,7 +101,20 @@ fn time_info_from_arr(arr: &[PinState]) -> Vec<TimeInfo>
 struct HeaterInfo
 {
     on: bool,
-    times: Vec<TimeInfo>
+    times: Vec<TimeInfo>,
+    sources: Vec<HeatSourceInfo>,
+    // current lifecycle phase and when it started
+    phase: Option<String>,
+    phase_since: Option<i64>
+}
+
+#[derive(new, Serialize, Deserialize)]
+struct HeatSourceInfo
+{
+    control_name: String,
+    control_pin: u8,
+    stage: u8,
+    on: bool
 }
 
 #[derive(new, Serialize, Deserialize)]
@@ -100,34 +127,114 @@ struct ZoneInfo
     expected_temperature: Option<f32>,
     states: Vec<TimeInfo>,
     dtc: Option<i64>,
+    override_temperature: Option<f32>,
+    override_expires: Option<i64>,
+    window_open_until: Option<i64>,
+    last_exercise: Option<i64>,
+    sensor_stale: bool,
+    sensor_unreported: bool,
+    sensor_last_seen: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct OverrideRequest
+{
+    zone: String,
+    temperature: f32,
+    // minutes, zero removes the override
+    duration: u32
 }
 
 #[derive(Serialize, Deserialize)]
 struct ControlInfo
 {
     name: String,
+    pump: Option<bool>,
     zones: Vec<ZoneInfo>
 }
 
+#[derive(Serialize, Deserialize)]
+struct AwayInfo
+{
+    active: bool,
+    start: Option<NaiveDate>,
+    end: Option<NaiveDate>,
+    temperature: f32
+}
+
+#[derive(new, Serialize, Deserialize)]
+struct HotWaterInfo
+{
+    name: String,
+    charging: bool,
+    priority: bool,
+    current_temperature: Option<f32>,
+    target_temperature: f32
+}
+
+// command sent to a node and not yet reported back
+#[derive(new, Serialize, Deserialize)]
+struct CommandInfo
+{
+    control_name: String,
+    pin: u8,
+    value: u16,
+    attempts: i32,
+    sent: Option<i64>,
+    last_sent: Option<i64>,
+    next_retry: Option<i64>,
+    failed: bool
+}
+
 #[derive(Serialize, Deserialize)]
 struct Info
 {
     heater: HeaterInfo,
-    controls: Vec<ControlInfo>
+    hot_water: Vec<HotWaterInfo>,
+    away: AwayInfo,
+    outdoor_temperature: Option<f32>,
+    controls: Vec<ControlInfo>,
+    pending_commands: Vec<CommandInfo>
 }
 
-fn load_info(db_path: &str, config: &Settings, control_nodes: &ControlNodes) -> Result<Info, String>
+fn load_info(db_path: &str, config: &Settings, control_nodes: &ControlNodes, now: &DateTime<Local>) -> Result<Info, String>
 {
     let connection = SqliteConnection::establish(db_path)
         .map_err(|e| format!("Unable to connect to db: {:?}", e))?;
-    let repository = PinStateRepository::new(&connection);
-    let last_heater_state = repository.get_last_pin_state(&config.heater_control_name(), config.heater_control_pin())
+    let repository = PinStateRepository::new(&connection).with_config(config);
+    let temperature_decider = TemperatureStateDecider::new(&repository, config);
+    let window_decider = WindowOpenDecider::new(&repository, config);
+    let stale_decider = StaleSensorDecider::new(&repository, config);
+    let hot_water_decider = HotWaterDecider::new(&repository, config);
+    let heater_decider = HeaterDecider::new(&repository, config);
+    let heat_sources = config.heat_sources();
+    let last_heater_state = get_heater_state(&repository, config)
         .map(|s| s.is_on()).unwrap_or(false);
-    let last_heater_times = repository.get_pin_changes(&config.heater_control_name(), config.heater_control_pin(), 6)
+    // times of the first stage
+    let last_heater_times = heat_sources.iter().min_by_key(|source| source.stage)
+        .and_then(|source| repository.get_pin_changes(&source.control_name, source.control_pin, 6))
         .map(|arr| time_info_from_arr(&arr)).unwrap_or(vec![]);
-    let now = Local::now();
+    let sources = heat_sources.iter()
+        .map(|source| HeatSourceInfo::new(
+            source.control_name.clone(),
+            source.control_pin,
+            source.stage,
+            repository.get_last_pin_state(&source.control_name, source.control_pin).map_or(false, |s| s.is_on())
+        ))
+        .collect();
+    let now = *now;
     let mut control_arr = Vec::new();
+    let mut hot_water = Vec::new();
     for (control_name, control_node) in control_nodes {
+        if let Some(tank) = &control_node.hot_water {
+            hot_water.push(HotWaterInfo::new(
+                control_name.to_owned(),
+                is_hot_water_charging(&repository, control_name, tank),
+                tank.priority,
+                hot_water_decider.get_tank_temperature(tank, &now).map(|t| t.value),
+                tank.target_temperature.value
+            ));
+        }
         let mut zones = Vec::new();
 
         for (zone_name, zone) in &control_node.zones {
@@ -135,20 +242,59 @@ fn load_info(db_path: &str, config: &Settings, control_nodes: &ControlNodes) ->
                 .map(|arr| time_info_from_arr(&arr)).unwrap_or(vec![]);
             let (on, timestamp) = repository.get_last_pin_state(control_name, zone.control_pin)
                 .map(|s| (s.is_on(), Some(s.dt.timestamp()))).unwrap_or((false, None));
+            let zone_override = repository.get_active_override(zone_name, &now);
             let zone_info = ZoneInfo::new(
                 zone_name.to_owned(),
                 zone.control_pin,
                 on,
-                repository.get_average_temperature(zone_name, zone.sensor_pin, &(now - Duration::hours(1))).map(|t| t.value),
-                zone.get_expected_temperature(&now.time()).map(|t| t.value),
+                repository.get_average_temperature(&zone.get_sensor_name(), zone.get_sensor_pin(), &(now - zone.get_temperature_average_time(config))).map(|t| t.value),
+                Some(temperature_decider.get_expected_temperature(zone, &now).value),
                 states,
-                timestamp
+                timestamp,
+                zone_override.as_ref().map(|o| o.temperature),
+                zone_override.as_ref().and_then(|o| Local.from_local_datetime(&o.expires).single()).map(|dt| dt.timestamp()),
+                window_decider.get_suspended_until(zone, &now).map(|dt| dt.timestamp()),
+                repository.get_last_event(zone_name, EVENT_EXERCISE)
+                    .and_then(|event| Local.from_local_datetime(&event.dtc).single())
+                    .map(|dt| dt.timestamp()),
+                stale_decider.is_stale(zone, &now),
+                stale_decider.is_unreported(zone),
+                stale_decider.get_last_seen(zone).map(|dt| dt.timestamp())
             );
             zones.push(zone_info);
         }
-        control_arr.push(ControlInfo { name: control_name.to_owned(), zones });
+        let pump = get_pump_pin(config, control_name, control_node)
+            .map(|pin| repository.get_last_pin_state(control_name, pin).map_or(false, |s| s.is_on()));
+        control_arr.push(ControlInfo { name: control_name.to_owned(), pump, zones });
     }
-    Ok(Info { heater: HeaterInfo { on: last_heater_state, times: last_heater_times}, controls: control_arr })
+    let away = AwayInfo {
+        active: config.is_away(&now.naive_local().date()),
+        start: config.away_start(),
+        end: config.away_end(),
+        temperature: config.away_temperature()
+    };
+    let outdoor_temperature = get_outdoor_temperature(&repository, config, &now).map(|t| t.value);
+    let pending_commands = repository.get_pending_commands().into_iter()
+        .filter_map(|command| command.get_pin_value().map(|(pin, value)| CommandInfo::new(
+            command.name.clone(),
+            pin,
+            value,
+            command.attempts,
+            Local.from_local_datetime(&command.dtc).single().map(|dt| dt.timestamp()),
+            Local.from_local_datetime(&command.last_sent).single().map(|dt| dt.timestamp()),
+            Local.from_local_datetime(&command.next_retry).single().map(|dt| dt.timestamp()),
+            command.failed
+        )))
+        .collect();
+    let phase = heater_decider.get_saved_phase();
+    let heater = HeaterInfo {
+        on: last_heater_state,
+        times: last_heater_times,
+        sources,
+        phase: phase.map(|(phase, _)| phase.name().to_owned()),
+        phase_since: phase.map(|(_, since)| since.timestamp())
+    };
+    Ok(Info { heater, hot_water, away, outdoor_temperature, controls: control_arr, pending_commands })
 
 }
 
@@ -157,10 +303,12 @@ fn show_config(settings: State<UiSettings>) -> Result<Html<String>, String>
 {
     let yaml_file = File::open(&settings.config_path).map_err(|_| "Unable to open config file")?;
     let reader = BufReader::new(yaml_file);
-    let full_config: FullConfig = serde_yaml::from_reader(reader).map_err(|_| "Unable to parse error")?;
+    let mut full_config: FullConfig = serde_yaml::from_reader(reader).map_err(|_| "Unable to parse error")?;
+    full_config.update_names();
+    full_config.redact_secrets();
 
     let config_json = serde_json::to_string(&full_config).map_err(|_| "Failed to serialize config to string")?;
-    let data = load_info(&settings.db_path,&Settings::new(full_config.general.clone()), &full_config.controls)?;
+    let data = load_info(&settings.db_path,&Settings::new(full_config.general.clone()), &full_config.controls, &settings.clock.now())?;
     let info_json = serde_json::to_string(&data).map_err(|_| "Failed to serialize info to string")?;
 
     let mut html_file = File::open(&settings.html_path).map_err(|_| "Unable to open html file")?;
@@ -173,12 +321,65 @@ fn show_config(settings: State<UiSettings>) -> Result<Html<String>, String>
 fn update_config(config: Json<FullConfig>, settings: State<UiSettings>) -> Result<JsonValue, JsonValue>
 {
     let json = serde_json::to_string(&config.into_inner()).map_err(|_| json!({"error": "Failed to serialize to string"}))?;
-    let full_config: FullConfig = serde_yaml::from_str(&json).map_err(|_| json!({"error": "Unable to parse error"}))?;
+    let mut full_config: FullConfig = serde_yaml::from_str(&json).map_err(|_| json!({"error": "Unable to parse error"}))?;
+    full_config.update_names();
+    let saved_file = File::open(&settings.config_path).map_err(|_| json!({"error": "Unable to open config file"}))?;
+    let saved_config: FullConfig = serde_yaml::from_reader(BufReader::new(saved_file)).map_err(|_| json!({"error": "Unable to parse config file"}))?;
+    full_config.keep_secrets(&saved_config);
     let yaml_file = File::create(&settings.config_path).map_err(|_| json!({"error": "Unable to open file"}))?;
     let writer = BufWriter::new(yaml_file);
     serde_yaml::to_writer(writer, &full_config).map_err(|_| json!({"error": "Unable to write to file"}))?;
+
+    let connection = SqliteConnection::establish(&settings.db_path)
+        .map_err(|e| json!({"error": format!("Unable to connect to db: {:?}", e)}))?;
+    let config = Settings::new(full_config.general.clone());
+    let repository = PinStateRepository::new(&connection).with_config(&config);
+    let stale_decider = StaleSensorDecider::new(&repository, &config);
+    let warnings: Vec<String> = stale_decider.get_unreported_zones(&full_config.controls).iter()
+        .map(|zone| format!("Zone {} sensor {} pin {} has not reported any temperature", zone.name, zone.get_sensor_name(), zone.get_sensor_pin()))
+        .collect();
+    Ok(json!({
+        "success": true,
+        "warnings": warnings,
+    }))
+}
+
+#[post("/override", format = "json", data = "<data>")]
+fn set_override(data: Json<OverrideRequest>, settings: State<UiSettings>) -> Result<JsonValue, JsonValue>
+{
+    let request = data.into_inner();
+    let yaml_file = File::open(&settings.config_path).map_err(|_| json!({"error": "Unable to open config file"}))?;
+    let full_config: FullConfig = serde_yaml::from_reader(BufReader::new(yaml_file)).map_err(|_| json!({"error": "Unable to parse error"}))?;
+    if !full_config.controls.values().any(|control| control.zones.contains_key(&request.zone)) {
+        return Err(json!({"error": format!("Unknown zone {}", request.zone)}));
+    }
+    let connection = SqliteConnection::establish(&settings.db_path)
+        .map_err(|e| json!({"error": format!("Unable to connect to db: {:?}", e)}))?;
+    let repository = PinStateRepository::new(&connection);
+    let now = settings.clock.now();
+    let result = if request.duration > 0 {
+        repository.save_override(&request.zone, request.temperature, &(now + Duration::minutes(request.duration as i64)), &now)
+    } else {
+        repository.remove_override(&request.zone)
+    };
+    result.map_err(|e| json!({"error": format!("Unable to save override: {:?}", e)}))?;
+
+    // the controller evaluates on the message instead of waiting for control_interval
+    let config = Settings::new(full_config.general);
+    let command = OverrideCommand::new(request.zone, request.temperature, request.duration);
+    let mut warnings: Vec<String> = Vec::new();
+    match connect_client(&config, "ui") {
+        Ok(client) => {
+            let sent = send_override(&client, &config.name(), &command) && client.do_loop(-1).is_ok();
+            if !sent {
+                warnings.push("Override was not sent to the controller, it applies within control_interval".to_owned());
+            }
+        },
+        Err(e) => warnings.push(format!("{}, override applies within control_interval", e))
+    }
     Ok(json!({
         "success": true,
+        "warnings": warnings,
     }))
 }
 
@@ -191,8 +392,8 @@ fn main() {
     let html_path = matches.value_of("html").unwrap_or("index.html");
     let db_path = matches.value_of("db").unwrap_or("pins.sqlite3");
     rocket::ignite()
-        .manage(UiSettings::new(config_path.to_owned(), html_path.to_owned(), db_path.to_owned()))
-        .mount("/", routes![show_config, update_config]).launch();
+        .manage(UiSettings::new(config_path.to_owned(), html_path.to_owned(), db_path.to_owned(), Box::new(SystemClock)))
+        .mount("/", routes![show_config, update_config, set_override]).launch();
 }
 
 
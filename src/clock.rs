@@ -0,0 +1,109 @@
+use chrono::{DateTime, Local, Duration};
+use std::cell::RefCell;
+use std::time::Instant;
+
+// source of current time, deciders get it as `now` so control logic can run on any clock
+pub trait Clock
+{
+    fn now(&self) -> DateTime<Local>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock
+{
+    fn now(&self) -> DateTime<Local>
+    {
+        Local::now()
+    }
+}
+
+// moves only when set or advanced, for tests
+pub struct ManualClock
+{
+    now: RefCell<DateTime<Local>>
+}
+
+impl ManualClock
+{
+    pub fn new(now: DateTime<Local>) -> ManualClock
+    {
+        ManualClock { now: RefCell::new(now) }
+    }
+
+    pub fn set(&self, now: DateTime<Local>)
+    {
+        self.now.replace(now);
+    }
+
+    pub fn advance(&self, duration: Duration)
+    {
+        let now = *self.now.borrow() + duration;
+        self.now.replace(now);
+    }
+}
+
+impl Clock for ManualClock
+{
+    fn now(&self) -> DateTime<Local>
+    {
+        *self.now.borrow()
+    }
+}
+
+// runs speed times faster than wall time from start, for simulations
+pub struct AcceleratedClock
+{
+    start: DateTime<Local>,
+    started: Instant,
+    speed: u32
+}
+
+impl AcceleratedClock
+{
+    pub fn new(start: DateTime<Local>, speed: u32) -> AcceleratedClock
+    {
+        AcceleratedClock { start, started: Instant::now(), speed: speed.max(1) }
+    }
+}
+
+impl Clock for AcceleratedClock
+{
+    fn now(&self) -> DateTime<Local>
+    {
+        let elapsed = self.started.elapsed() * self.speed;
+        self.start + Duration::from_std(elapsed).unwrap_or_else(|_| Duration::zero())
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use speculate::speculate;
+    use super::*;
+    use chrono::TimeZone;
+
+    speculate! {
+        describe "clocks"
+        {
+            it "should move manual clock only when told"
+            {
+                let clock = ManualClock::new(Local.ymd(2019, 8, 1).and_hms(8, 0, 0));
+                assert_eq!(clock.now(), Local.ymd(2019, 8, 1).and_hms(8, 0, 0));
+                clock.advance(Duration::minutes(90));
+                assert_eq!(clock.now(), Local.ymd(2019, 8, 1).and_hms(9, 30, 0));
+                clock.set(Local.ymd(2019, 8, 2).and_hms(7, 0, 0));
+                assert_eq!(clock.now(), Local.ymd(2019, 8, 2).and_hms(7, 0, 0));
+            }
+
+            it "should run accelerated clock faster"
+            {
+                let start = Local.ymd(2019, 8, 1).and_hms(8, 0, 0);
+                let clock = AcceleratedClock::new(start, 3600);
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                assert!(clock.now() - start >= Duration::seconds(36));
+            }
+        }
+    }
+}
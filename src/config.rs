@@ -2,12 +2,15 @@ use std::collections::HashMap;
 use log::{error, debug};
 use std::time::{UNIX_EPOCH};
 
-use crate::zone::Zone;
+use crate::zone::{Zone, HotWaterZone, FailSafe};
 use std::fs::{metadata, File};
 use std::io::{Error, ErrorKind, BufReader};
 use std::cell::{RefCell};
 use serde::{Serialize, Deserialize};
 use derive_new::{new};
+use chrono::{NaiveDate, NaiveTime, Weekday, Datelike};
+use arduino_mqtt_pin::pin::{PinValue, Temperature};
+use arduino_mqtt_pin::helper::percent_to_analog;
 
 pub type ControlNodes = HashMap<String, ControlNode>;
 pub type Zones = HashMap<String, Zone>;
@@ -19,6 +22,38 @@ pub struct FullConfig
     pub controls: ControlNodes
 }
 
+impl FullConfig
+{
+    // names are provided as yaml keys
+    // zones without sensor_node have used their name, it is kept so renaming a zone keeps its sensor
+    pub fn update_names(&mut self)
+    {
+        for (control_name, control_node) in self.controls.iter_mut() {
+            control_node.name = control_name.clone();
+            for (zone_name, zone) in control_node.zones.iter_mut() {
+                zone.name = zone_name.clone();
+                if zone.sensor_node.is_none() {
+                    zone.sensor_node = Some(zone_name.clone());
+                }
+            }
+        }
+    }
+
+    // the ui gets the config as json, secrets stay in the config file
+    pub fn redact_secrets(&mut self)
+    {
+        self.general.mqtt.password = None;
+    }
+
+    // config posted back from the ui has no password, the saved one is kept
+    pub fn keep_secrets(&mut self, saved: &FullConfig)
+    {
+        if self.general.mqtt.password.is_none() {
+            self.general.mqtt.password = saved.general.mqtt.password.clone();
+        }
+    }
+}
+
 #[derive(Debug, new, Serialize, Deserialize)]
 pub struct ControlNode
 {
@@ -26,7 +61,188 @@ pub struct ControlNode
     pub name: String,
     #[serde(default)]
     pub control_pin: u8,
-    pub zones: Zones
+    pub zones: Zones,
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hot_water: Option<HotWaterZone>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PinOutput
+{
+    Digital,
+    Analog
+}
+
+// boiler, heat pump or any other source, lower stages start first
+#[derive(Debug, new, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HeatSource
+{
+    pub control_name: String,
+    pub control_pin: u8,
+    #[new(value = "PinOutput::Digital")]
+    #[serde(default = "default_heat_source_output")]
+    pub output: PinOutput,
+    // analog output in percent while on
+    #[new(value = "100")]
+    #[serde(default = "default_heat_source_power")]
+    pub power: u8,
+    #[new(default)]
+    #[serde(default)]
+    pub stage: u8,
+    // enabled only while outdoor temperature is within limits
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_outdoor_temperature: Option<f32>,
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_outdoor_temperature: Option<f32>,
+    // enabled only from enabled_from till enabled_to when both are set, may wrap past midnight
+    #[new(default)]
+    #[serde(default, with = "crate::zone::serde_option_naive_time", skip_serializing_if = "Option::is_none")]
+    pub enabled_from: Option<NaiveTime>,
+    #[new(default)]
+    #[serde(default, with = "crate::zone::serde_option_naive_time", skip_serializing_if = "Option::is_none")]
+    pub enabled_to: Option<NaiveTime>
+}
+
+impl HeatSource
+{
+    pub fn get_value(&self, on: bool) -> PinValue
+    {
+        match (self.output, on) {
+            (PinOutput::Digital, on) => PinValue::Digital(on),
+            (PinOutput::Analog, true) => PinValue::Analog(percent_to_analog(self.power)),
+            (PinOutput::Analog, false) => PinValue::Analog(0)
+        }
+    }
+
+    // missing outdoor readings keep the source enabled
+    pub fn is_enabled(&self, outdoor_temperature: &Option<Temperature>, time: &NaiveTime) -> bool
+    {
+        if let Some(t) = outdoor_temperature {
+            if self.min_outdoor_temperature.map_or(false, |min| t.value < min)
+                || self.max_outdoor_temperature.map_or(false, |max| t.value > max) {
+                return false;
+            }
+        }
+        match (self.enabled_from, self.enabled_to) {
+            (Some(from), Some(to)) if from <= to => *time >= from && *time < to,
+            (Some(from), Some(to)) => *time >= from || *time < to,
+            _ => true
+        }
+    }
+}
+
+fn default_heat_source_output() -> PinOutput
+{
+    PinOutput::Digital
+}
+
+fn default_heat_source_power() -> u8
+{
+    100
+}
+
+// how readings within a period are combined into one temperature
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TemperatureAggregation
+{
+    Mean,
+    Median,
+    // mean without the lowest and highest temperature_trim part of readings
+    TrimmedMean,
+    // exponential moving average, later readings weigh temperature_ema_alpha
+    Ema
+}
+
+// rejects sensor errors e.g. -127 of a disconnected DS18B20 or 85 after its reset
+#[derive(Debug, new, Clone, Copy, PartialEq)]
+pub struct TemperatureFilter
+{
+    pub aggregation: TemperatureAggregation,
+    #[new(value = "0.2")]
+    pub trim: f32,
+    #[new(value = "0.3")]
+    pub ema_alpha: f32,
+    #[new(value = "-55.0")]
+    pub min: f32,
+    #[new(value = "125.0")]
+    pub max: f32,
+    // readings further than this from the window median and from all their neighbours are spikes
+    #[new(value = "Some(10.0)")]
+    pub max_jump: Option<f32>
+}
+
+impl TemperatureFilter
+{
+    pub fn is_possible(&self, temperature: f32) -> bool
+    {
+        temperature.is_finite() && temperature >= self.min && temperature <= self.max
+    }
+
+    // readings ordered by time
+    pub fn filter<T: Clone>(&self, readings: &[(T, f32)]) -> Vec<(T, f32)>
+    {
+        let possible: Vec<&(T, f32)> = readings.iter().filter(|(_, t)| self.is_possible(*t)).collect();
+        let max_jump = match self.max_jump {
+            Some(max_jump) => max_jump,
+            None => return possible.into_iter().cloned().collect()
+        };
+        let mut sorted: Vec<f32> = possible.iter().map(|(_, t)| *t).collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let median = match median(&sorted) {
+            Some(median) => median,
+            None => return vec![]
+        };
+        // a step change keeps the readings next to each other, two readings are never both spikes
+        let is_jump = |i: Option<usize>, t: f32| i.and_then(|i| possible.get(i)).map_or(true, |(_, other)| (t - other).abs() > max_jump);
+        possible.iter().enumerate()
+            .filter(|(i, (_, t))| (*t - median).abs() <= max_jump || !(is_jump(i.checked_sub(1), *t) && is_jump(Some(i + 1), *t)))
+            .map(|(_, reading)| (*reading).clone())
+            .collect()
+    }
+
+    // readings ordered by time
+    pub fn aggregate(&self, temperatures: &[f32]) -> Option<f32>
+    {
+        if temperatures.is_empty() {
+            return None;
+        }
+        let mut sorted = temperatures.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let mean = |values: &[f32]| values.iter().sum::<f32>() / values.len() as f32;
+        Some(match self.aggregation {
+            TemperatureAggregation::Mean => mean(temperatures),
+            TemperatureAggregation::Median => median(&sorted)?,
+            TemperatureAggregation::TrimmedMean => {
+                let trim = ((sorted.len() as f32 * self.trim.max(0.0).min(0.5)) as usize).min((sorted.len() - 1) / 2);
+                mean(&sorted[trim..sorted.len() - trim])
+            },
+            TemperatureAggregation::Ema => temperatures[1..].iter()
+                .fold(temperatures[0], |ema, t| self.ema_alpha * t + (1.0 - self.ema_alpha) * ema)
+        })
+    }
+}
+
+// values sorted ascending
+fn median(sorted: &[f32]) -> Option<f32>
+{
+    match sorted.len() {
+        0 => None,
+        len if len % 2 == 0 => Some((sorted[len / 2 - 1] + sorted[len / 2]) / 2.0),
+        len => Some(sorted[len / 2])
+    }
+}
+
+impl Default for TemperatureFilter
+{
+    fn default() -> TemperatureFilter
+    {
+        TemperatureFilter::new(TemperatureAggregation::Mean)
+    }
 }
 
 #[derive(Debug)]
@@ -57,6 +273,11 @@ impl Settings
         self.config.borrow().host.clone()
     }
 
+    pub fn mqtt(&self) -> MqttConfig
+    {
+        self.config.borrow().mqtt.clone()
+    }
+
     pub fn heater_control_name(&self) -> String
     {
         self.config.borrow().heater_control_name.clone()
@@ -82,6 +303,11 @@ impl Settings
         self.config.borrow().constant_temperature_expected
     }
 
+    pub fn constant_temperature_enabled(&self) -> bool
+    {
+        self.config.borrow().constant_temperature_enabled
+    }
+
     pub fn min_pwm_state(&self) -> u8
     {
         self.config.borrow().min_pwm_state
@@ -97,6 +323,225 @@ impl Settings
         self.config.borrow().temperature_drop_wait
     }
 
+    pub fn temperature_average_time(&self) -> u16
+    {
+        self.config.borrow().temperature_average_time
+    }
+
+    pub fn tank_average_time(&self) -> u16
+    {
+        self.config.borrow().tank_average_time
+    }
+
+    pub fn setback_temperature(&self) -> Option<f32>
+    {
+        self.config.borrow().setback_temperature
+    }
+
+    pub fn frost_protection_temperature(&self) -> f32
+    {
+        self.config.borrow().frost_protection_temperature
+    }
+
+    pub fn away_start(&self) -> Option<NaiveDate>
+    {
+        self.config.borrow().away_start
+    }
+
+    pub fn away_end(&self) -> Option<NaiveDate>
+    {
+        self.config.borrow().away_end
+    }
+
+    pub fn away_temperature(&self) -> f32
+    {
+        self.config.borrow().away_temperature
+    }
+
+    pub fn time_proportional_cycle(&self) -> u16
+    {
+        self.config.borrow().time_proportional_cycle
+    }
+
+    pub fn outdoor_sensor_name(&self) -> Option<String>
+    {
+        self.config.borrow().outdoor_sensor_name.clone()
+    }
+
+    pub fn outdoor_sensor_pin(&self) -> u8
+    {
+        self.config.borrow().outdoor_sensor_pin
+    }
+
+    pub fn heating_curve_slope(&self) -> f32
+    {
+        self.config.borrow().heating_curve_slope
+    }
+
+    pub fn heating_curve_reference(&self) -> f32
+    {
+        self.config.borrow().heating_curve_reference
+    }
+
+    pub fn heating_curve_max_offset(&self) -> f32
+    {
+        self.config.borrow().heating_curve_max_offset
+    }
+
+    pub fn outdoor_cutoff_temperature(&self) -> Option<f32>
+    {
+        self.config.borrow().outdoor_cutoff_temperature
+    }
+
+    pub fn outdoor_average_time(&self) -> u16
+    {
+        self.config.borrow().outdoor_average_time
+    }
+
+    pub fn window_open_drop_rate(&self) -> Option<f32>
+    {
+        self.config.borrow().window_open_drop_rate
+    }
+
+    pub fn window_open_detection_time(&self) -> u16
+    {
+        self.config.borrow().window_open_detection_time
+    }
+
+    pub fn window_open_suspend_time(&self) -> u16
+    {
+        self.config.borrow().window_open_suspend_time
+    }
+
+    pub fn zone_min_on_time(&self) -> u16
+    {
+        self.config.borrow().zone_min_on_time
+    }
+
+    pub fn zone_min_off_time(&self) -> u16
+    {
+        self.config.borrow().zone_min_off_time
+    }
+
+    pub fn heater_min_on_time(&self) -> u16
+    {
+        self.config.borrow().heater_min_on_time
+    }
+
+    pub fn heater_min_off_time(&self) -> u16
+    {
+        self.config.borrow().heater_min_off_time
+    }
+
+    pub fn heater_max_starts_per_hour(&self) -> Option<u8>
+    {
+        self.config.borrow().heater_max_starts_per_hour
+    }
+
+    pub fn exercise_enabled(&self) -> bool
+    {
+        self.config.borrow().exercise_enabled
+    }
+
+    pub fn exercise_time(&self) -> NaiveTime
+    {
+        self.config.borrow().exercise_time
+    }
+
+    pub fn exercise_duration(&self) -> u16
+    {
+        self.config.borrow().exercise_duration
+    }
+
+    // no days means every day
+    pub fn is_exercise_day(&self, date: &NaiveDate) -> bool
+    {
+        let config = self.config.borrow();
+        config.exercise_enabled && (config.exercise_days.is_empty() || config.exercise_days.contains(&date.weekday()))
+    }
+
+    pub fn pump_overrun_time(&self) -> u16
+    {
+        self.config.borrow().pump_overrun_time
+    }
+
+    // pins are evaluated on relevant messages and config changes, at least every control_interval secs
+    pub fn control_interval(&self) -> u16
+    {
+        self.config.borrow().control_interval
+    }
+
+    // first retry of an unacknowledged command, doubled after every attempt
+    pub fn command_retry_time(&self) -> u16
+    {
+        self.config.borrow().command_retry_time
+    }
+
+    pub fn command_max_attempts(&self) -> u8
+    {
+        self.config.borrow().command_max_attempts
+    }
+
+    pub fn max_open_zones(&self) -> Option<u8>
+    {
+        self.config.borrow().max_open_zones
+    }
+
+    // a zone waiting this long takes the slot of a zone open this long with lower or equal priority
+    pub fn open_zone_slice_time(&self) -> u16
+    {
+        self.config.borrow().open_zone_slice_time
+    }
+
+    // heater_control_name and heater_control_pin are the only source unless heat_sources are set
+    pub fn heat_sources(&self) -> Vec<HeatSource>
+    {
+        let config = self.config.borrow();
+        if config.heat_sources.is_empty() {
+            vec![HeatSource::new(config.heater_control_name.clone(), config.heater_control_pin)]
+        } else {
+            config.heat_sources.clone()
+        }
+    }
+
+    pub fn heat_source_stage_delay(&self) -> u16
+    {
+        self.config.borrow().heat_source_stage_delay
+    }
+
+    pub fn temperature_filter(&self) -> TemperatureFilter
+    {
+        let config = self.config.borrow();
+        TemperatureFilter {
+            aggregation: config.temperature_aggregation,
+            trim: config.temperature_trim,
+            ema_alpha: config.temperature_ema_alpha,
+            min: config.temperature_min,
+            max: config.temperature_max,
+            max_jump: config.temperature_max_jump
+        }
+    }
+
+    pub fn sensor_stale_timeout(&self) -> u16
+    {
+        self.config.borrow().sensor_stale_timeout
+    }
+
+    pub fn fail_safe(&self) -> FailSafe
+    {
+        self.config.borrow().fail_safe
+    }
+
+    // away period includes both start and end dates
+    pub fn is_away(&self, date: &NaiveDate) -> bool
+    {
+        let config = self.config.borrow();
+        match (config.away_start, config.away_end) {
+            (Some(start), Some(end)) => start <= *date && *date <= end,
+            _ => false
+        }
+    }
+
     pub fn version(&self) -> u64
     {
         self.config.borrow().version
@@ -104,11 +549,77 @@ impl Settings
 }
 
 
+// broker connection shared by every binary talking mqtt
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MqttConfig
+{
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    // first line of the file is the password, keeps it out of the config shown in the ui
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<String>,
+    // tls is used when ca_file is set, cert_file and key_file authenticate the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<String>,
+    // general name is used when not set, binaries add their own suffix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>
+}
+
+impl Default for MqttConfig
+{
+    fn default() -> MqttConfig
+    {
+        MqttConfig {
+            port: default_mqtt_port(),
+            username: None,
+            password: None,
+            password_file: None,
+            ca_file: None,
+            cert_file: None,
+            key_file: None,
+            client_id: None
+        }
+    }
+}
+
+impl MqttConfig
+{
+    pub fn get_password(&self) -> Result<Option<String>, Error>
+    {
+        match (&self.password, &self.password_file) {
+            (Some(password), _) => Ok(Some(password.clone())),
+            (None, Some(path)) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| Error::new(ErrorKind::NotFound, format!("Unable to read mqtt password file {} {}", path, e)))?;
+                Ok(contents.lines().next().map(|line| line.trim().to_owned()))
+            },
+            (None, None) => Ok(None)
+        }
+    }
+}
+
+fn default_mqtt_port() -> u16
+{
+    1883
+}
+
 #[derive(Debug, new, Serialize, Deserialize, Clone)]
 pub struct Config
 {
     name: String,
     host: String,
+    #[new(default)]
+    #[serde(default)]
+    mqtt: MqttConfig,
     heater_control_name: String,
     heater_control_pin: u8,
     #[new(value = "300")]
@@ -117,17 +628,279 @@ pub struct Config
     heater_pump_stop_time: u16,
     #[new(value = "20.0")]
     constant_temperature_expected: f32,
+    #[new(default)]
+    #[serde(default)]
+    constant_temperature_enabled: bool,
     #[new(value = "30")]
     min_pwm_state: u8,
     #[new(value = "0.3")]
     min_temperature_diff_for_pwm: f32,
     #[new(value = "0.7")]
     temperature_drop_wait: f32,
+    #[new(value = "1800")]
+    #[serde(default = "default_temperature_average_time")]
+    temperature_average_time: u16,
+    #[new(value = "600")]
+    #[serde(default = "default_tank_average_time")]
+    tank_average_time: u16,
+    #[new(default)]
+    #[serde(default)]
+    setback_temperature: Option<f32>,
+    #[new(value = "5.0")]
+    #[serde(default = "default_frost_protection_temperature")]
+    frost_protection_temperature: f32,
+    #[new(default)]
+    #[serde(default)]
+    away_start: Option<NaiveDate>,
+    #[new(default)]
+    #[serde(default)]
+    away_end: Option<NaiveDate>,
+    #[new(value = "16.0")]
+    #[serde(default = "default_away_temperature")]
+    away_temperature: f32,
+    #[new(value = "1200")]
+    #[serde(default = "default_time_proportional_cycle")]
+    time_proportional_cycle: u16,
+    #[new(default)]
+    #[serde(default)]
+    outdoor_sensor_name: Option<String>,
+    #[new(default)]
+    #[serde(default)]
+    outdoor_sensor_pin: u8,
+    #[new(default)]
+    #[serde(default)]
+    heating_curve_slope: f32,
+    #[new(value = "15.0")]
+    #[serde(default = "default_heating_curve_reference")]
+    heating_curve_reference: f32,
+    #[new(value = "3.0")]
+    #[serde(default = "default_heating_curve_max_offset")]
+    heating_curve_max_offset: f32,
+    #[new(default)]
+    #[serde(default)]
+    outdoor_cutoff_temperature: Option<f32>,
+    #[new(value = "1800")]
+    #[serde(default = "default_outdoor_average_time")]
+    outdoor_average_time: u16,
+    #[new(default)]
+    #[serde(default)]
+    window_open_drop_rate: Option<f32>,
+    #[new(value = "600")]
+    #[serde(default = "default_window_open_detection_time")]
+    window_open_detection_time: u16,
+    #[new(value = "1800")]
+    #[serde(default = "default_window_open_suspend_time")]
+    window_open_suspend_time: u16,
+    #[new(default)]
+    #[serde(default)]
+    zone_min_on_time: u16,
+    #[new(default)]
+    #[serde(default)]
+    zone_min_off_time: u16,
+    #[new(default)]
+    #[serde(default)]
+    heater_min_on_time: u16,
+    #[new(default)]
+    #[serde(default)]
+    heater_min_off_time: u16,
+    #[new(default)]
+    #[serde(default)]
+    heater_max_starts_per_hour: Option<u8>,
+    #[new(default)]
+    #[serde(default)]
+    exercise_enabled: bool,
+    #[new(default)]
+    #[serde(default, with = "crate::zone::serde_weekdays", skip_serializing_if = "Vec::is_empty")]
+    exercise_days: Vec<Weekday>,
+    #[new(value = "NaiveTime::from_hms(11, 0, 0)")]
+    #[serde(default = "default_exercise_time", with = "crate::zone::serde_naive_time")]
+    exercise_time: NaiveTime,
+    #[new(value = "60")]
+    #[serde(default = "default_exercise_duration")]
+    exercise_duration: u16,
+    #[new(default)]
+    #[serde(default)]
+    max_open_zones: Option<u8>,
+    #[new(value = "1800")]
+    #[serde(default = "default_open_zone_slice_time")]
+    open_zone_slice_time: u16,
+    #[new(value = "300")]
+    #[serde(default = "default_pump_overrun_time")]
+    pump_overrun_time: u16,
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    heat_sources: Vec<HeatSource>,
+    #[new(value = "1800")]
+    #[serde(default = "default_heat_source_stage_delay")]
+    heat_source_stage_delay: u16,
+    #[new(value = "1800")]
+    #[serde(default = "default_sensor_stale_timeout")]
+    sensor_stale_timeout: u16,
+    #[new(value = "FailSafe::Off")]
+    #[serde(default = "default_fail_safe")]
+    fail_safe: FailSafe,
+    #[new(value = "TemperatureAggregation::Mean")]
+    #[serde(default = "default_temperature_aggregation")]
+    temperature_aggregation: TemperatureAggregation,
+    #[new(value = "0.2")]
+    #[serde(default = "default_temperature_trim")]
+    temperature_trim: f32,
+    #[new(value = "0.3")]
+    #[serde(default = "default_temperature_ema_alpha")]
+    temperature_ema_alpha: f32,
+    #[new(value = "-55.0")]
+    #[serde(default = "default_temperature_min")]
+    temperature_min: f32,
+    #[new(value = "125.0")]
+    #[serde(default = "default_temperature_max")]
+    temperature_max: f32,
+    #[new(value = "Some(10.0)")]
+    #[serde(default = "default_temperature_max_jump")]
+    temperature_max_jump: Option<f32>,
+    #[new(value = "30")]
+    #[serde(default = "default_control_interval")]
+    control_interval: u16,
+    #[new(value = "30")]
+    #[serde(default = "default_command_retry_time")]
+    command_retry_time: u16,
+    #[new(value = "5")]
+    #[serde(default = "default_command_max_attempts")]
+    command_max_attempts: u8,
     #[new(value = "0")]
     #[serde(default)]
     version: u64
 }
 
+fn default_frost_protection_temperature() -> f32
+{
+    5.0
+}
+
+fn default_temperature_average_time() -> u16
+{
+    1800
+}
+
+fn default_tank_average_time() -> u16
+{
+    600
+}
+
+fn default_outdoor_average_time() -> u16
+{
+    1800
+}
+
+fn default_away_temperature() -> f32
+{
+    16.0
+}
+
+fn default_time_proportional_cycle() -> u16
+{
+    1200
+}
+
+fn default_heating_curve_reference() -> f32
+{
+    15.0
+}
+
+fn default_heating_curve_max_offset() -> f32
+{
+    3.0
+}
+
+fn default_exercise_time() -> NaiveTime
+{
+    NaiveTime::from_hms(11, 0, 0)
+}
+
+fn default_exercise_duration() -> u16
+{
+    60
+}
+
+fn default_pump_overrun_time() -> u16
+{
+    300
+}
+
+fn default_open_zone_slice_time() -> u16
+{
+    1800
+}
+
+fn default_heat_source_stage_delay() -> u16
+{
+    1800
+}
+
+fn default_control_interval() -> u16
+{
+    30
+}
+
+fn default_command_retry_time() -> u16
+{
+    30
+}
+
+fn default_command_max_attempts() -> u8
+{
+    5
+}
+
+fn default_sensor_stale_timeout() -> u16
+{
+    1800
+}
+
+fn default_fail_safe() -> FailSafe
+{
+    FailSafe::Off
+}
+
+fn default_temperature_aggregation() -> TemperatureAggregation
+{
+    TemperatureAggregation::Mean
+}
+
+fn default_temperature_trim() -> f32
+{
+    0.2
+}
+
+fn default_temperature_ema_alpha() -> f32
+{
+    0.3
+}
+
+fn default_temperature_min() -> f32
+{
+    -55.0
+}
+
+fn default_temperature_max() -> f32
+{
+    125.0
+}
+
+fn default_temperature_max_jump() -> Option<f32>
+{
+    Some(10.0)
+}
+
+fn default_window_open_detection_time() -> u16
+{
+    600
+}
+
+fn default_window_open_suspend_time() -> u16
+{
+    1800
+}
+
 pub fn load_config(config_path: &str, verbosity: u8) -> Result<(Config, ControlNodes), Error>
 {
 
@@ -141,6 +914,8 @@ pub fn load_config(config_path: &str, verbosity: u8) -> Result<(Config, ControlN
 
     debug!("Config loaded: {} Verbosity: {}", config_path, verbosity);
 
+    full_config.update_names();
+
     let version =  metadata(config_path)
         .and_then(|meta| meta.modified())
         .map(|stime| if let Ok(dur) = stime.duration_since(UNIX_EPOCH) { dur.as_secs() } else { 0 })
@@ -164,13 +939,54 @@ pub fn has_config_changed(config_path: &str, version: u64) -> bool
 }
 
 #[cfg(test)]
-mod tests
+pub mod tests
 {
 
     use speculate::speculate;
     use super::*;
     use serde_yaml;
     use serde_json;
+    use crate::zone::{PidSettings, Strategy};
+    use crate::embedded_migrations;
+    use diesel::{SqliteConnection, Connection};
+
+    // general config with defaults and the provided yaml lines appended
+    pub fn create_settings(extra: &str) -> Settings
+    {
+        Settings::new(create_config(extra))
+    }
+
+    fn create_config(extra: &str) -> Config
+    {
+        let contents = format!("
+name: test
+host: host
+heater_control_name: main
+heater_control_pin: 34
+acctuator_warmup_time: 300
+heater_pump_stop_time: 600
+constant_temperature_expected: 20.0
+min_pwm_state: 30
+min_temperature_diff_for_pwm: 0.3
+temperature_drop_wait: 0.7
+{}
+", extra);
+        serde_yaml::from_str(&contents).unwrap()
+    }
+
+    // config as the ui reads and posts it back
+    fn round_trip(config: Config) -> Settings
+    {
+        Settings::new(serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap())
+    }
+
+    // migrated in memory db with settings from create_settings
+    pub fn create_fixture(extra: &str) -> (SqliteConnection, Settings)
+    {
+        let connection = SqliteConnection::establish(":memory:").unwrap();
+        embedded_migrations::run(&connection).unwrap();
+        (connection, create_settings(extra))
+    }
 
     speculate! {
         describe "config serialization"
@@ -240,6 +1056,421 @@ controls:
                 let config: FullConfig = serde_yaml::from_str(&contents).unwrap();
                 let json = serde_json::to_string(&config).unwrap();
             }
+
+            it "should keep mqtt settings and secrets"
+            {
+                let contents = "
+general:
+  name: test
+  host: host
+  heater_control_name: main
+  heater_control_pin: 34
+  mqtt:
+    port: 8883
+    username: heating
+    password: secret
+    ca_file: /etc/mosquitto/ca.crt
+controls: {}
+                ";
+                let config: FullConfig = serde_yaml::from_str(&contents).unwrap();
+                let mqtt = round_trip(config.general.clone()).mqtt();
+                assert_eq!((mqtt.port, mqtt.username.as_ref().map(|u| u.as_str())), (8883, Some("heating")));
+                assert_eq!(mqtt.get_password().unwrap(), Some("secret".to_owned()));
+                assert_eq!(mqtt.ca_file, Some("/etc/mosquitto/ca.crt".to_owned()));
+                assert!(mqtt.cert_file.is_none());
+
+                let mut shown: FullConfig = serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+                shown.redact_secrets();
+                let shown_json = serde_json::to_string(&shown).unwrap();
+                assert!(!shown_json.contains("secret"), "{}", shown_json);
+                shown.keep_secrets(&config);
+                assert_eq!(shown.general.mqtt.password, Some("secret".to_owned()));
+            }
+
+            it "should round trip setback and frost protection"
+            {
+                let settings = round_trip(create_config("setback_temperature: 16.5\nfrost_protection_temperature: 6.0"));
+                assert_eq!(settings.setback_temperature(), Some(16.5));
+                assert_eq!(settings.frost_protection_temperature(), 6.0);
+            }
+
+            it "should round trip away mode"
+            {
+                let settings = round_trip(create_config("away_start: 2019-12-20\naway_end: 2020-01-02\naway_temperature: 15.0"));
+                assert_eq!(settings.away_start(), Some(NaiveDate::from_ymd(2019, 12, 20)));
+                assert_eq!(settings.away_end(), Some(NaiveDate::from_ymd(2020, 1, 2)));
+                assert_eq!(settings.away_temperature(), 15.0);
+                assert_eq!(round_trip(create_config("")).away_temperature(), default_away_temperature());
+            }
+
+            it "should round trip constant temperature mode"
+            {
+                assert!(round_trip(create_config("constant_temperature_enabled: true")).constant_temperature_enabled());
+            }
+
+            it "should round trip time proportional cycle"
+            {
+                assert_eq!(round_trip(create_config("time_proportional_cycle: 900")).time_proportional_cycle(), 900);
+                assert_eq!(round_trip(create_config("")).time_proportional_cycle(), default_time_proportional_cycle());
+            }
+
+            it "should round trip weather compensation"
+            {
+                let settings = round_trip(create_config("
+outdoor_sensor_name: outside
+outdoor_sensor_pin: 5
+heating_curve_slope: 0.2
+heating_curve_reference: 12.0
+heating_curve_max_offset: 2.5
+outdoor_cutoff_temperature: 17.0
+outdoor_average_time: 900
+tank_average_time: 300
+"));
+                assert_eq!(settings.outdoor_sensor_name(), Some("outside".to_owned()));
+                assert_eq!(settings.outdoor_sensor_pin(), 5);
+                assert_eq!((settings.heating_curve_slope(), settings.heating_curve_reference(), settings.heating_curve_max_offset()), (0.2, 12.0, 2.5));
+                assert_eq!(settings.outdoor_cutoff_temperature(), Some(17.0));
+                assert_eq!((settings.outdoor_average_time(), settings.tank_average_time()), (900, 300));
+                let settings = round_trip(create_config(""));
+                assert!(settings.outdoor_sensor_name().is_none());
+                assert_eq!(settings.heating_curve_reference(), default_heating_curve_reference());
+                assert_eq!(settings.heating_curve_max_offset(), default_heating_curve_max_offset());
+            }
+
+            it "should round trip window detection"
+            {
+                let settings = round_trip(create_config("window_open_drop_rate: 0.15\nwindow_open_detection_time: 600\nwindow_open_suspend_time: 1200"));
+                assert_eq!(settings.window_open_drop_rate(), Some(0.15));
+                assert_eq!((settings.window_open_detection_time(), settings.window_open_suspend_time()), (600, 1200));
+                let settings = round_trip(create_config(""));
+                assert!(settings.window_open_drop_rate().is_none());
+                assert_eq!(settings.window_open_detection_time(), default_window_open_detection_time());
+                assert_eq!(settings.window_open_suspend_time(), default_window_open_suspend_time());
+            }
+
+            it "should round trip anti short cycling"
+            {
+                let settings = round_trip(create_config("
+zone_min_on_time: 300
+zone_min_off_time: 240
+heater_min_on_time: 600
+heater_min_off_time: 420
+heater_max_starts_per_hour: 3
+"));
+                assert_eq!((settings.zone_min_on_time(), settings.zone_min_off_time()), (300, 240));
+                assert_eq!((settings.heater_min_on_time(), settings.heater_min_off_time()), (600, 420));
+                assert_eq!(settings.heater_max_starts_per_hour(), Some(3));
+                assert!(round_trip(create_config("")).heater_max_starts_per_hour().is_none());
+            }
+
+            it "should round trip valve exercise"
+            {
+                let settings = round_trip(create_config("exercise_enabled: true\nexercise_days: Sun\nexercise_time: 11:30\nexercise_duration: 90"));
+                assert!(settings.exercise_enabled());
+                assert_eq!(settings.exercise_time(), NaiveTime::from_hms(11, 30, 0));
+                assert_eq!(settings.exercise_duration(), 90);
+                assert!(settings.is_exercise_day(&NaiveDate::from_ymd(2019, 12, 22)));
+                assert!(!settings.is_exercise_day(&NaiveDate::from_ymd(2019, 12, 23)));
+                let settings = round_trip(create_config(""));
+                assert!(!settings.exercise_enabled());
+                assert_eq!(settings.exercise_time(), default_exercise_time());
+                assert_eq!(settings.exercise_duration(), default_exercise_duration());
+            }
+
+            it "should round trip open zone limit"
+            {
+                let settings = round_trip(create_config("max_open_zones: 4\nopen_zone_slice_time: 900"));
+                assert_eq!(settings.max_open_zones(), Some(4));
+                assert_eq!(settings.open_zone_slice_time(), 900);
+                let settings = round_trip(create_config(""));
+                assert!(settings.max_open_zones().is_none());
+                assert_eq!(settings.open_zone_slice_time(), default_open_zone_slice_time());
+            }
+
+            it "should round trip pump overrun"
+            {
+                assert_eq!(round_trip(create_config("pump_overrun_time: 120")).pump_overrun_time(), 120);
+                assert_eq!(round_trip(create_config("")).pump_overrun_time(), default_pump_overrun_time());
+            }
+
+            it "should round trip heat sources"
+            {
+                let settings = round_trip(create_config("
+heat_sources:
+  - control_name: main
+    control_pin: 40
+    output: analog
+    power: 80
+    min_outdoor_temperature: -7.0
+  - control_name: main
+    control_pin: 34
+    stage: 1
+    enabled_from: 22:00
+    enabled_to: 7:00
+heat_source_stage_delay: 900
+"));
+                let sources = settings.heat_sources();
+                assert_eq!(sources.len(), 2);
+                assert_eq!(sources[0].output, PinOutput::Analog);
+                assert_eq!(sources[0].min_outdoor_temperature, Some(-7.0));
+                assert_eq!(sources[0].get_value(true), PinValue::Analog(percent_to_analog(80)));
+                assert_eq!(sources[1].get_value(true), PinValue::Digital(true));
+                assert_eq!(sources[1].enabled_from, Some(NaiveTime::from_hms(22, 0, 0)));
+                assert_eq!(sources[1].enabled_to, Some(NaiveTime::from_hms(7, 0, 0)));
+                assert_eq!(settings.heat_source_stage_delay(), 900);
+                assert_eq!(round_trip(create_config("")).heat_source_stage_delay(), default_heat_source_stage_delay());
+            }
+
+            it "should round trip control interval"
+            {
+                assert_eq!(round_trip(create_config("control_interval: 30")).control_interval(), 30);
+                assert_eq!(round_trip(create_config("")).control_interval(), default_control_interval());
+            }
+
+            it "should round trip command retry"
+            {
+                let settings = round_trip(create_config("command_retry_time: 20\ncommand_max_attempts: 5"));
+                assert_eq!((settings.command_retry_time(), settings.command_max_attempts()), (20, 5));
+                let settings = round_trip(create_config(""));
+                assert_eq!(settings.command_retry_time(), default_command_retry_time());
+                assert_eq!(settings.command_max_attempts(), default_command_max_attempts());
+            }
+
+            it "should round trip stale sensor fail safe"
+            {
+                let settings = round_trip(create_config("
+sensor_stale_timeout: 1200
+fail_safe:
+  mode: duty_cycle
+  on_time: 600
+  cycle: 1800
+"));
+                assert_eq!(settings.sensor_stale_timeout(), 1200);
+                assert_eq!(settings.fail_safe(), FailSafe::DutyCycle { on_time: 600, cycle: 1800 });
+            }
+
+            it "should round trip temperature filter"
+            {
+                let filter = round_trip(create_config("
+temperature_aggregation: trimmed_mean
+temperature_trim: 0.1
+temperature_min: -40.0
+temperature_max: 100.0
+temperature_max_jump: ~
+")).temperature_filter();
+                assert_eq!(filter.aggregation, TemperatureAggregation::TrimmedMean);
+                assert_eq!(filter.trim, 0.1);
+                assert_eq!(filter.ema_alpha, default_temperature_ema_alpha());
+                assert_eq!((filter.min, filter.max, filter.max_jump), (-40.0, 100.0, None));
+            }
+
+            it "should round trip zone settings"
+            {
+                let contents = "
+general:
+  name: test
+  host: host
+  heater_control_name: main
+  heater_control_pin: 34
+controls:
+  main:
+    path: test/nodes/main
+    zones:
+      salionas:
+        times:
+          - start: 4:00
+            end: 21:00
+            expected_temperature: 21.0
+        sensor_pin: 2
+        control_pin: 4
+        setback_temperature: 18.0
+        priority: 2
+        pid:
+          kp: 40.0
+          ki: 0.5
+      vaiku:
+        times: []
+        control_pin: 9
+        sensor_pin: 2
+        strategy: time_proportional
+        fail_safe:
+          mode: pwm
+          power: 40
+                ";
+                let config: FullConfig = serde_yaml::from_str(&contents).unwrap();
+                let config: FullConfig = serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+                let salionas = &config.controls["main"].zones["salionas"];
+                let vaiku = &config.controls["main"].zones["vaiku"];
+                assert_eq!(salionas.setback_temperature, Some(Temperature::new(18.0)));
+                assert!(vaiku.setback_temperature.is_none());
+                assert_eq!((salionas.priority, vaiku.priority), (2, 0));
+                assert_eq!(salionas.pid, Some(PidSettings::new(40.0, 0.5, 0.0)));
+                assert!(vaiku.pid.is_none());
+                assert_eq!((salionas.get_strategy(), vaiku.get_strategy()), (Strategy::Pid, Strategy::TimeProportional));
+                assert_eq!(vaiku.fail_safe, Some(FailSafe::Pwm { power: 40 }));
+                assert!(salionas.fail_safe.is_none());
+            }
+
+            it "should name controls and zone sensors"
+            {
+                let contents = "
+general:
+  name: test
+  host: host
+  heater_control_name: main
+  heater_control_pin: 34
+controls:
+  slave:
+    path: test/nodes/slave
+    zones:
+      miegamasis:
+        times: []
+        control_pin: 10
+        sensor_node: bedroom
+        sensor_address: 28-FF-4A-1C-64-16-03-5B
+      vaiku:
+        times: []
+        control_pin: 9
+        sensor_pin: 2
+                ";
+                let mut config: FullConfig = serde_yaml::from_str(&contents).unwrap();
+                config.update_names();
+                let config: FullConfig = serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+                assert_eq!(config.controls["slave"].name, "slave");
+                assert_eq!(config.controls["slave"].zones["vaiku"].name, "vaiku");
+                assert_eq!(config.controls["slave"].zones["vaiku"].sensor_node, Some("vaiku".to_owned()));
+                assert_eq!(config.controls["slave"].zones["vaiku"].get_sensor_name(), "vaiku");
+                assert_eq!(config.controls["slave"].zones["miegamasis"].get_sensor_name(), "bedroom:28ff4a1c6416035b");
+                assert_eq!(config.controls["slave"].zones["miegamasis"].get_sensor_pin(), 0);
+            }
+
+            it "should round trip hot water"
+            {
+                let contents = "
+general:
+  name: test
+  host: host
+  heater_control_name: main
+  heater_control_pin: 34
+controls:
+  main:
+    path: test/nodes/main
+    zones: {}
+  slave:
+    path: test/nodes/slave
+    zones: {}
+    hot_water:
+      sensor_name: boiler
+      sensor_pin: 3
+      control_pin: 12
+      target_temperature: 55.0
+      priority: true
+                ";
+                let config: FullConfig = serde_yaml::from_str(&contents).unwrap();
+                let config: FullConfig = serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+                let hot_water = config.controls["slave"].hot_water.clone().expect("hot water");
+                assert_eq!(hot_water.target_temperature, Temperature::new(55.0));
+                assert_eq!(hot_water.hysteresis, 5.0);
+                assert!(hot_water.priority);
+                assert!(config.controls["main"].hot_water.is_none());
+            }
+
+            it "should fall back to heater control pin"
+            {
+                let settings = create_settings("");
+                assert_eq!(settings.heat_sources(), vec![HeatSource::new("main".to_owned(), 34)]);
+            }
+
+            it "should enable heat source by conditions"
+            {
+                let mut source = HeatSource::new("main".to_owned(), 40);
+                let noon = NaiveTime::from_hms(12, 0, 0);
+                let night = NaiveTime::from_hms(23, 0, 0);
+                assert!(source.is_enabled(&None, &noon));
+                source.min_outdoor_temperature = Some(-5.0);
+                source.max_outdoor_temperature = Some(10.0);
+                assert!(source.is_enabled(&Some(Temperature::new(0.0)), &noon));
+                assert!(!source.is_enabled(&Some(Temperature::new(-6.0)), &noon));
+                assert!(!source.is_enabled(&Some(Temperature::new(11.0)), &noon));
+                assert!(source.is_enabled(&None, &noon));
+                source.enabled_from = Some(NaiveTime::from_hms(22, 0, 0));
+                source.enabled_to = Some(NaiveTime::from_hms(7, 0, 0));
+                assert!(source.is_enabled(&None, &night));
+                assert!(source.is_enabled(&None, &NaiveTime::from_hms(6, 59, 0)));
+                assert!(!source.is_enabled(&None, &noon));
+                source.enabled_from = Some(NaiveTime::from_hms(8, 0, 0));
+                source.enabled_to = Some(NaiveTime::from_hms(20, 0, 0));
+                assert!(source.is_enabled(&None, &noon));
+                assert!(!source.is_enabled(&None, &night));
+            }
+
+            it "should be away within period"
+            {
+                let settings = create_settings("away_start: 2019-12-20\naway_end: 2020-01-02\naway_temperature: 15.0");
+                assert_eq!(settings.away_temperature(), 15.0);
+                for (expected, date) in vec![
+                    (false, NaiveDate::from_ymd(2019, 12, 19)),
+                    (true, NaiveDate::from_ymd(2019, 12, 20)),
+                    (true, NaiveDate::from_ymd(2019, 12, 31)),
+                    (true, NaiveDate::from_ymd(2020, 1, 2)),
+                    (false, NaiveDate::from_ymd(2020, 1, 3)),
+                ] {
+                    assert_eq!(settings.is_away(&date), expected, "{}", date);
+                }
+
+                let settings = create_settings("away_start: 2019-12-20");
+                assert!(!settings.is_away(&NaiveDate::from_ymd(2019, 12, 21)));
+                assert!(!create_settings("").is_away(&NaiveDate::from_ymd(2019, 12, 21)));
+            }
+
+            it "should use default setback settings"
+            {
+                let settings = create_settings("");
+                assert!(!settings.constant_temperature_enabled());
+                assert_eq!(settings.setback_temperature(), None);
+                assert_eq!(settings.frost_protection_temperature(), 5.0);
+            }
+
+            it "should close stale zones by default"
+            {
+                let settings = create_settings("");
+                assert_eq!(settings.sensor_stale_timeout(), 1800);
+                assert_eq!(settings.fail_safe(), FailSafe::Off);
+            }
+
+            it "should average outdoor and tank temperatures by default"
+            {
+                let settings = create_settings("");
+                assert_eq!(settings.outdoor_average_time(), 1800);
+                assert_eq!(settings.tank_average_time(), 600);
+            }
+
+            it "should connect to plain mqtt by default"
+            {
+                let mqtt = create_settings("").mqtt();
+                assert_eq!(mqtt, MqttConfig::default());
+                assert_eq!(mqtt.port, 1883);
+                assert_eq!(mqtt.get_password().unwrap(), None);
+            }
+
+            it "should read mqtt password from file"
+            {
+                let path = std::env::temp_dir().join("heating-control-mqtt-password");
+                std::fs::write(&path, "from-file\n").unwrap();
+                let settings = create_settings(&format!("mqtt:\n  username: heating\n  password_file: {}", path.display()));
+                assert_eq!(settings.mqtt().get_password().unwrap(), Some("from-file".to_owned()));
+                std::fs::remove_file(&path).unwrap();
+
+                let settings = create_settings("mqtt:\n  password_file: /nonexistent/heating-password");
+                assert!(settings.mqtt().get_password().is_err());
+            }
+
+            it "should filter temperatures by default"
+            {
+                let settings = create_settings("");
+                assert_eq!(settings.temperature_filter(), TemperatureFilter::default());
+                assert_eq!(TemperatureFilter::default().max_jump, Some(10.0));
+            }
         }
     }
 }
\ No newline at end of file
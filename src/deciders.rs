@@ -1,67 +1,190 @@
-use chrono::{DateTime, Local, Duration};
+use chrono::{DateTime, Local, Duration, TimeZone};
 use arduino_mqtt_pin::pin::{PinState, PinValue, Temperature};
-use crate::config::{ControlNodes, Settings};
-use crate::repository::PinStateRepository;
+use crate::config::{ControlNodes, ControlNode, Settings, HeatSource};
+use crate::repository::{PinStateRepository, PendingCommand, EVENT_WINDOW_OPEN, EVENT_SWITCH_BLOCKED, EVENT_EXERCISE, EVENT_EXERCISE_RUN, EVENT_EXERCISE_MISSED, EVENT_SENSOR_STALE, EVENT_SENSOR_UNREPORTED, EVENT_COMMAND_FAILED, EVENT_HEATER_PHASE};
 use arduino_mqtt_pin::helper::percent_to_analog;
-use crate::zone::{Zone};
+use crate::zone::{Zone, Strategy, HotWaterZone, FailSafe};
+use crate::strategies::{ZoneDecider, HysteresisStrategy, ProportionalStrategy, PidStrategy, TimeProportionalStrategy};
 use derive_new::{new};
+use std::collections::HashMap;
+use log::{info, warn};
 
-#[derive(new)]
+// selects zone strategy by zone configuration
 pub struct ZoneStateDecider<'a>
 {
-    temp_decider: &'a TemperatureStateDecider<'a>,
-    config: &'a Settings
+    hysteresis: HysteresisStrategy<'a>,
+    proportional: ProportionalStrategy<'a>,
+    pid: PidStrategy<'a>,
+    time_proportional: TimeProportionalStrategy<'a>
 }
 
-impl ZoneStateDecider<'_>
+impl<'a> ZoneStateDecider<'a>
 {
-
-    pub fn should_be_on(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> bool
+    pub fn new(repository: &'a PinStateRepository<'a>, temp_decider: &'a TemperatureStateDecider<'a>, config: &'a Settings) -> ZoneStateDecider<'a>
     {
-        if let Some(expected_temperature) = zone.get_expected_temperature(&now.time()) {
-            if last_state.is_on() {
-                *current_temperature < expected_temperature
-            } else {
-                *current_temperature < expected_temperature - Temperature::new(self.config.temperature_drop_wait())
-            }
-        } else {
-            false
+        ZoneStateDecider {
+            hysteresis: HysteresisStrategy::new(temp_decider, config),
+            proportional: ProportionalStrategy::new(temp_decider, config),
+            pid: PidStrategy::new(repository, temp_decider, config),
+            time_proportional: TimeProportionalStrategy::new(temp_decider, config)
         }
     }
 
-    pub fn get_value_to_change_to(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> Option<PinValue>
+    fn get_strategy(&self, zone: &Zone) -> &dyn ZoneDecider
     {
-        let zone_should_be_on = self.should_be_on(last_state, zone, current_temperature, now);
-        if last_state.is_on() && !zone_should_be_on {
-            return Some(PinValue::Analog(0u16));
+        match zone.get_strategy() {
+            Strategy::Hysteresis => &self.hysteresis,
+            Strategy::Proportional => &self.proportional,
+            Strategy::Pid => &self.pid,
+            Strategy::TimeProportional => &self.time_proportional
         }
-        if !last_state.is_on() && zone_should_be_on {
-            return Some(self.temp_decider.get_expected_value(current_temperature, zone, now));
-        }
-        None
+    }
+}
+
+impl ZoneDecider for ZoneStateDecider<'_>
+{
+    fn should_be_on(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> bool
+    {
+        self.get_strategy(zone).should_be_on(last_state, zone, current_temperature, now)
+    }
+
+    fn get_value_to_change_to(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> Option<PinValue>
+    {
+        self.get_strategy(zone).get_value_to_change_to(last_state, zone, current_temperature, now)
+    }
+}
+
+// average outdoor temperature for the last outdoor_average_time secs when outdoor sensor is configured
+pub fn get_outdoor_temperature(repository: &PinStateRepository, config: &Settings, now: &DateTime<Local>) -> Option<Temperature>
+{
+    let since = *now - Duration::seconds(config.outdoor_average_time() as i64);
+    config.outdoor_sensor_name()
+        .and_then(|name| repository.get_average_temperature(&name, config.outdoor_sensor_pin(), &since))
+}
+
+// events about all heat sources are saved with this name
+pub const HEATER_EVENT_NAME: &str = "heater";
+// valve exercise runs as a whole, zones have their own exercise events
+pub const EXERCISE_EVENT_NAME: &str = "exercise";
+
+// on while any heat source is on since the earliest of them, otherwise off since the latest one
+pub fn get_heater_state(repository: &PinStateRepository, config: &Settings) -> Option<PinState>
+{
+    let states: Vec<PinState> = config.heat_sources().iter()
+        .filter_map(|source| repository.get_last_changed_pin_state(&source.control_name, source.control_pin))
+        .collect();
+    states.iter().filter(|state| state.is_on()).min_by_key(|state| state.dt).cloned()
+        .or_else(|| states.into_iter().max_by_key(|state| state.dt))
+}
+
+// node pump pin is not used when it is not set or drives a heat source
+pub fn get_pump_pin(config: &Settings, control_name: &str, control_node: &ControlNode) -> Option<u8>
+{
+    let is_heat_source = config.heat_sources().iter()
+        .any(|source| source.control_name == control_name && source.control_pin == control_node.control_pin);
+    if control_node.control_pin == 0 || is_heat_source {
+        return None;
+    }
+    Some(control_node.control_pin)
+}
+
+// tank charging pin is on
+pub fn is_hot_water_charging(repository: &PinStateRepository, control_name: &str, tank: &HotWaterZone) -> bool
+{
+    repository.get_last_pin_state(control_name, tank.control_pin).map_or(false, |state| state.is_on())
+}
+
+// missing outdoor readings never suppress heating
+fn is_above_outdoor_cutoff(config: &Settings, outdoor_temperature: &Option<Temperature>) -> bool
+{
+    match (config.outdoor_cutoff_temperature(), outdoor_temperature) {
+        (Some(cutoff), Some(t)) => t.value > cutoff,
+        _ => false
     }
 }
 
 #[derive(new)]
 pub struct TemperatureStateDecider<'a>
 {
+    repository: &'a PinStateRepository<'a>,
     config: &'a Settings
 }
 
 impl TemperatureStateDecider<'_>
 {
-    pub fn get_expected_value(&self, current_temperature: &Temperature, zone: &Zone, now: &DateTime<Local>) -> PinValue
+    // zone override even when warm outside, then frost protection only above the outdoor cutoff,
+    // away or constant temperature as configured, otherwise zone schedule falling back to zone and general setback
+    // temperatures raised by the heating curve when outdoor temperature is low
+    // never lower than frost protection temperature
+    pub fn get_expected_temperature(&self, zone: &Zone, now: &DateTime<Local>) -> Temperature
     {
-        let expected_temperature = match zone.get_expected_temperature(&now.time()) {
-            Some(t) => t,
-            _ => return PinValue::Analog(0)
+        let frost_temperature = Temperature::new(self.config.frost_protection_temperature());
+        let expected_temperature = match self.repository.get_active_override(&zone.name, now) {
+            Some(zone_override) => Some(Temperature::new(zone_override.temperature)),
+            None => {
+                let outdoor_temperature = get_outdoor_temperature(self.repository, self.config, now);
+                if is_above_outdoor_cutoff(self.config, &outdoor_temperature) {
+                    return frost_temperature;
+                }
+                self.get_away_temperature(now)
+                    .or_else(|| self.get_constant_temperature())
+                    .or_else(|| self.get_scheduled_temperature(zone, now)
+                        .map(|t| Temperature::new(t.value + self.get_heating_curve_offset(&outdoor_temperature)))
+                    )
+            }
         };
+        match expected_temperature {
+            Some(t) => if t > frost_temperature { t } else { frost_temperature },
+            None => frost_temperature
+        }
+    }
+
+    fn get_scheduled_temperature(&self, zone: &Zone, now: &DateTime<Local>) -> Option<Temperature>
+    {
+        zone.get_expected_temperature(&now.naive_local())
+            .or_else(|| zone.setback_temperature.clone())
+            .or_else(|| self.config.setback_temperature().map(|t| Temperature::new(t)))
+    }
+
+    // degrees to add to the target for the current outdoor temperature
+    pub fn get_heating_curve_offset(&self, outdoor_temperature: &Option<Temperature>) -> f32
+    {
+        match outdoor_temperature {
+            Some(t) => (self.config.heating_curve_slope() * (self.config.heating_curve_reference() - t.value))
+                .max(0f32)
+                .min(self.config.heating_curve_max_offset()),
+            None => 0f32
+        }
+    }
+
+    fn get_away_temperature(&self, now: &DateTime<Local>) -> Option<Temperature>
+    {
+        if self.config.is_away(&now.naive_local().date()) {
+            Some(Temperature::new(self.config.away_temperature()))
+        } else {
+            None
+        }
+    }
+
+    fn get_constant_temperature(&self) -> Option<Temperature>
+    {
+        if self.config.constant_temperature_enabled() {
+            Some(Temperature::new(self.config.constant_temperature_expected()))
+        } else {
+            None
+        }
+    }
+
+    // temperature difference mapped to pwm linearly
+    pub fn get_expected_value(&self, current_temperature: &Temperature, zone: &Zone, now: &DateTime<Local>) -> PinValue
+    {
+        let expected_temperature = self.get_expected_temperature(zone, now);
         if *current_temperature >= expected_temperature {
             return PinValue::Analog(0);
         }
         let diff = (expected_temperature - current_temperature.clone()).abs();
-        let value = if diff <= Temperature::new(self.config.min_temperature_diff_for_pwm()) {
-            percent_to_analog(self.config.min_pwm_state())
+        let value = if diff <= Temperature::new(zone.get_min_temperature_diff_for_pwm(self.config)) {
+            percent_to_analog(zone.get_min_pwm_state(self.config))
         } else if diff < Temperature::new(1f32) {
             percent_to_analog((diff.value * 100f32) as u8)
         } else {
@@ -72,112 +195,1784 @@ impl TemperatureStateDecider<'_>
 }
 
 #[derive(new)]
-pub struct HeaterDecider<'a>
+pub struct WindowOpenDecider<'a>
 {
     repository: &'a PinStateRepository<'a>,
     config: &'a Settings
 }
 
-impl HeaterDecider<'_>
+impl WindowOpenDecider<'_>
 {
-    pub fn should_be_on(&self, nodes: &ControlNodes, now: &DateTime<Local>) -> bool
+    // end of the current open window suspension
+    pub fn get_suspended_until(&self, zone: &Zone, now: &DateTime<Local>) -> Option<DateTime<Local>>
     {
-        if let Some(first_zone_on) = self.repository.get_first_zone_on_dt(nodes, &(*now - Duration::hours(24))) {
-            return *now - first_zone_on > Duration::seconds(self.config.acctuator_warmup_time() as i64);
+        self.repository.get_last_event(&zone.name, EVENT_WINDOW_OPEN)
+            .and_then(|event| Local.from_local_datetime(&event.dtc).single())
+            .filter(|dt| dt <= now)
+            .map(|dt| dt + Duration::seconds(self.config.window_open_suspend_time() as i64))
+            .filter(|until| until > now)
+    }
+
+    // least squares slope of the readings in the detection window in degrees per minute, falling is positive
+    // readings before the end of the last suspension are ignored and they have to span half of the window
+    // so a single sensor step is not taken for a fall
+    pub fn get_fall_rate(&self, zone: &Zone, now: &DateTime<Local>) -> Option<f32>
+    {
+        let detection_time = self.config.window_open_detection_time() as i64;
+        let detection_start = *now - Duration::seconds(detection_time);
+        let since = self.repository.get_last_event(&zone.name, EVENT_WINDOW_OPEN)
+            .and_then(|event| Local.from_local_datetime(&event.dtc).single())
+            .map(|dt| dt + Duration::seconds(self.config.window_open_suspend_time() as i64))
+            .filter(|suspension_end| *suspension_end > detection_start)
+            .unwrap_or(detection_start);
+        let readings: Vec<(DateTime<Local>, Temperature)> = self.repository.get_temperatures(&zone.get_sensor_name(), zone.get_sensor_pin(), &since)
+            .into_iter()
+            .filter(|(dt, _)| dt <= now)
+            .collect();
+        let (first_dt, _) = readings.first()?;
+        let (last_dt, _) = readings.last()?;
+        if (*last_dt - *first_dt).num_seconds() < (detection_time / 2).max(1) {
+            return None;
         }
-        false
+        let points: Vec<(f32, f32)> = readings.iter()
+            .map(|(dt, t)| ((*dt - *first_dt).num_seconds() as f32 / 60f32, t.value))
+            .collect();
+        let count = points.len() as f32;
+        let mean_minute = points.iter().map(|(minute, _)| minute).sum::<f32>() / count;
+        let mean_temperature = points.iter().map(|(_, temperature)| temperature).sum::<f32>() / count;
+        let covariance: f32 = points.iter().map(|(minute, temperature)| (minute - mean_minute) * (temperature - mean_temperature)).sum();
+        let variance: f32 = points.iter().map(|(minute, _)| (minute - mean_minute).powi(2)).sum();
+        Some(-covariance / variance)
     }
 
-    pub fn can_turn_zones_off(&self, state: &PinState, now: &DateTime<Local>) -> bool
+    fn get_fast_fall_rate(&self, zone: &Zone, now: &DateTime<Local>) -> Option<f32>
     {
-        !state.is_on() && *now - state.dt > Duration::seconds(self.config.heater_pump_stop_time() as i64)
+        let drop_rate = self.config.window_open_drop_rate()?;
+        self.get_fall_rate(zone, now).filter(|fall_rate| *fall_rate >= drop_rate)
+    }
+
+    // zone is suspended or its temperature falls too fast
+    pub fn is_window_open(&self, zone: &Zone, now: &DateTime<Local>) -> bool
+    {
+        self.get_suspended_until(zone, now).is_some() || self.get_fast_fall_rate(zone, now).is_some()
+    }
+
+    // starts a suspension on a fast fall, called once per evaluation
+    pub fn record_suspension(&self, zone: &Zone, now: &DateTime<Local>)
+    {
+        if self.get_suspended_until(zone, now).is_some() {
+            return;
+        }
+        if let Some(fall_rate) = self.get_fast_fall_rate(zone, now) {
+            let message = format!("Temperature falling {:.2} degrees per minute, heating suspended for {} secs", fall_rate, self.config.window_open_suspend_time());
+            info!("Window open in zone {}: {}", zone.name, message);
+            if let Err(e) = self.repository.save_event(&zone.name, EVENT_WINDOW_OPEN, &message, now) {
+                warn!("Unable to save window open event for {} {:?}", zone.name, e);
+            }
+        }
     }
 }
 
+// zones use fail safe output while their sensor is not reporting
+#[derive(new)]
+pub struct StaleSensorDecider<'a>
+{
+    repository: &'a PinStateRepository<'a>,
+    config: &'a Settings
+}
 
-#[cfg(test)]
-mod test_deciders
+impl StaleSensorDecider<'_>
 {
-    use super::*;
-    use chrono::{TimeZone, NaiveTime};
-    use crate::repository::test_repository::{create_nodes, create_repository};
-    use crate::zone::{Interval};
-    use crate::config::{Config};
-    use diesel::{SqliteConnection, Connection};
-    use crate::embedded_migrations;
+    pub fn get_last_seen(&self, zone: &Zone) -> Option<DateTime<Local>>
+    {
+        self.repository.get_last_temperature_dt(&zone.get_sensor_name(), zone.get_sensor_pin())
+    }
 
-    fn create_zone() -> (Zone, Settings)
+    // only sensors that have reported before can go stale
+    pub fn is_stale(&self, zone: &Zone, now: &DateTime<Local>) -> bool
     {
-        let config = Settings::new(Config::new(String::from("test"), String::from("host"), String::from("main"), 3));
-        let intervals = vec![
-            Interval::new(NaiveTime::from_hms(8, 0, 0), NaiveTime::from_hms(9, 0, 0), Temperature::new(20.0)),
-            Interval::new(NaiveTime::from_hms(23, 1, 0), NaiveTime::from_hms(23, 3, 3), Temperature::new(30.5))
-        ];
-        (Zone::new(String::from("zone1"), 1, intervals, 2), config)
+        self.get_last_seen(zone)
+            .map_or(false, |dt| *now - dt > Duration::seconds(self.config.sensor_stale_timeout() as i64))
     }
 
-    speculate! {
-        describe "zone temperature"
-        {
-            before
-            {
-                let (zone, config) = create_zone();
-                let decider = TemperatureStateDecider::new(&config);
-            }
+    pub fn is_unreported(&self, zone: &Zone) -> bool
+    {
+        self.get_last_seen(zone).is_none()
+    }
 
-            it "should match value"
-            {
-                for (expected, temp, hour) in vec![
-                    (1023, 19.0, 8),
-                    (306, 19.8, 8),
-                    (716, 19.3, 8),
-                    (1023, 2.3, 8),
-                    (0, 20.0, 8),
-                    (0, 20.0, 9),
-                ] {
-                    assert_eq!(
-                        decider.get_expected_value(
-                            &Temperature::new(temp), &zone, &Local.ymd(2019, 8, 1).and_hms(hour, 0, 0)
-                        ).as_u16(),
-                        expected
-                    );
-                }
+    // zones whose sensor has never reported, usually a typo in sensor address
+    pub fn get_unreported_zones<'b>(&self, control_nodes: &'b ControlNodes) -> Vec<&'b Zone>
+    {
+        control_nodes.values()
+            .flat_map(|control_node| control_node.zones.values())
+            .filter(|zone| self.is_unreported(zone))
+            .collect()
+    }
+
+    pub fn get_fail_safe(&self, zone: &Zone) -> FailSafe
+    {
+        zone.fail_safe.unwrap_or_else(|| self.config.fail_safe())
+    }
+
+    pub fn get_fail_safe_value(&self, zone: &Zone, now: &DateTime<Local>) -> PinValue
+    {
+        match self.get_fail_safe(zone) {
+            FailSafe::Off => PinValue::Analog(0u16),
+            FailSafe::Pwm { power } => PinValue::Analog(percent_to_analog(power)),
+            FailSafe::DutyCycle { on_time, cycle } if cycle > 0 && now.timestamp() % cycle as i64 >= on_time as i64 => PinValue::Analog(0u16),
+            FailSafe::DutyCycle { .. } => PinValue::Analog(percent_to_analog(100))
+        }
+    }
+
+    // alarm is raised once for every time the sensor goes stale
+    pub fn report_stale(&self, zone: &Zone, now: &DateTime<Local>)
+    {
+        let last_seen = match self.get_last_seen(zone) {
+            Some(dt) => dt,
+            None => return
+        };
+        let reported = self.repository.get_last_event(&zone.name, EVENT_SENSOR_STALE)
+            .map_or(false, |event| event.dtc >= last_seen.naive_local());
+        if reported {
+            return;
+        }
+        let message = format!("No readings from sensor {} pin {} since {}, using fail safe {:?}", zone.get_sensor_name(), zone.get_sensor_pin(), last_seen, self.get_fail_safe(zone));
+        warn!("Zone {} sensor stale: {}", zone.name, message);
+        if let Err(e) = self.repository.save_event(&zone.name, EVENT_SENSOR_STALE, &message, now) {
+            warn!("Unable to save sensor stale event for {} {:?}", zone.name, e);
+        }
+    }
+
+    // alarm is raised once, the zone stays closed until the sensor reports
+    pub fn report_unreported(&self, zone: &Zone, now: &DateTime<Local>)
+    {
+        if self.repository.get_last_event(&zone.name, EVENT_SENSOR_UNREPORTED).is_some() {
+            return;
+        }
+        let message = format!("No readings from sensor {} pin {} yet, zone kept closed", zone.get_sensor_name(), zone.get_sensor_pin());
+        warn!("Zone {} sensor unreported: {}", zone.name, message);
+        if let Err(e) = self.repository.save_event(&zone.name, EVENT_SENSOR_UNREPORTED, &message, now) {
+            warn!("Unable to save sensor unreported event for {} {:?}", zone.name, e);
+        }
+    }
+}
+
+// keeps zone valves and heater from switching on and off too often
+#[derive(new)]
+pub struct CycleDecider<'a>
+{
+    repository: &'a PinStateRepository<'a>,
+    config: &'a Settings
+}
+
+impl CycleDecider<'_>
+{
+    pub fn can_switch_zone(&self, control_name: &str, zone: &Zone, turn_on: bool, now: &DateTime<Local>) -> bool
+    {
+        let min_time = if turn_on { self.config.zone_min_off_time() } else { self.config.zone_min_on_time() };
+        let state = self.repository.get_last_changed_pin_state(control_name, zone.control_pin);
+        self.has_state_lasted(state, &format!("Pin {}", zone.control_pin), &zone.name, min_time, now)
+    }
+
+    // node pumps keep zone timings
+    pub fn can_switch_pump(&self, control_name: &str, pin: u8, turn_on: bool, now: &DateTime<Local>) -> bool
+    {
+        let min_time = if turn_on { self.config.zone_min_off_time() } else { self.config.zone_min_on_time() };
+        let state = self.repository.get_last_changed_pin_state(control_name, pin);
+        self.has_state_lasted(state, &format!("Pump pin {}", pin), control_name, min_time, now)
+    }
+
+    // all heat sources together, starts of every source are counted
+    pub fn can_switch_heater(&self, turn_on: bool, now: &DateTime<Local>) -> bool
+    {
+        let state = get_heater_state(self.repository, self.config);
+        if !turn_on {
+            return self.has_state_lasted(state, "Heater", HEATER_EVENT_NAME, self.config.heater_min_on_time(), now);
+        }
+        if !self.has_state_lasted(state, "Heater", HEATER_EVENT_NAME, self.config.heater_min_off_time(), now) {
+            return false;
+        }
+        if let Some(max_starts) = self.config.heater_max_starts_per_hour() {
+            let mut starts: Vec<PinState> = self.config.heat_sources().iter()
+                .flat_map(|source| self.repository.get_pin_changes(&source.control_name, source.control_pin, max_starts as usize * 2).unwrap_or(vec![]))
+                .filter(|state| state.is_on() && state.dt > *now - Duration::hours(1))
+                .collect();
+            starts.sort_by(|a, b| b.dt.cmp(&a.dt));
+            if starts.len() >= max_starts as usize {
+                let message = format!("Heater started {} times within an hour, last at {}", starts.len(), starts[0].dt);
+                self.report_blocked(HEATER_EVENT_NAME, &message, now);
+                return false;
             }
         }
+        true
+    }
 
-        describe "zone states"
-        {
-            before
-            {
-                let (zone, config) = create_zone();
-                let temp_decider = TemperatureStateDecider::new(&config);
-                let zone_decider = ZoneStateDecider::new(&temp_decider, &config);
+    // current state has been kept for at least min_time secs
+    fn has_state_lasted(&self, state: Option<PinState>, label: &str, event_name: &str, min_time: u16, now: &DateTime<Local>) -> bool
+    {
+        if min_time == 0 {
+            return true;
+        }
+        match state {
+            Some(state) if *now - state.dt < Duration::seconds(min_time as i64) => {
+                let message = format!("{} {} since {}, switching allowed after {} secs", label, if state.is_on() { "on" } else { "off" }, state.dt, min_time);
+                self.report_blocked(event_name, &message, now);
+                false
+            },
+            _ => true
+        }
+    }
+
+    // messages name the blocking state so each one is reported once
+    fn report_blocked(&self, event_name: &str, message: &str, now: &DateTime<Local>)
+    {
+        if self.repository.get_last_event(event_name, EVENT_SWITCH_BLOCKED).map_or(false, |event| event.message == message) {
+            return;
+        }
+        info!("Switch blocked for {}: {}", event_name, message);
+        if let Err(e) = self.repository.save_event(event_name, EVENT_SWITCH_BLOCKED, message, now) {
+            warn!("Unable to save switch blocked event for {} {:?}", event_name, e);
+        }
+    }
+}
+
+// node pump runs while any zone valve or tank of the node is open and pump_overrun_time secs after they close
+#[derive(new)]
+pub struct PumpDecider<'a>
+{
+    repository: &'a PinStateRepository<'a>,
+    config: &'a Settings
+}
+
+impl PumpDecider<'_>
+{
+    fn get_demand_pins(&self, control_node: &ControlNode) -> Vec<u8>
+    {
+        control_node.zones.values().map(|zone| zone.control_pin)
+            .chain(control_node.hot_water.as_ref().map(|tank| tank.control_pin))
+            .collect()
+    }
+
+    pub fn has_demand(&self, control_name: &str, control_node: &ControlNode) -> bool
+    {
+        self.get_demand_pins(control_node).iter()
+            .any(|pin| self.repository.get_last_pin_state(control_name, *pin).map_or(false, |state| state.is_on()))
+    }
+
+    // when the last zone valve or tank of the node closed
+    pub fn get_demand_end(&self, control_name: &str, control_node: &ControlNode) -> Option<DateTime<Local>>
+    {
+        self.get_demand_pins(control_node).iter()
+            .filter_map(|pin| self.repository.get_last_changed_pin_state(control_name, *pin))
+            .filter(|state| !state.is_on())
+            .map(|state| state.dt)
+            .max()
+    }
+
+    pub fn get_pins_to_change(&self, control_nodes: &ControlNodes, now: &DateTime<Local>) -> HashMap<String, HashMap<u8, PinValue>>
+    {
+        let mut changes: HashMap<String, HashMap<u8, PinValue>> = HashMap::new();
+        for (control_name, control_node) in control_nodes {
+            let pump_pin = match get_pump_pin(self.config, control_name, control_node) {
+                Some(pin) => pin,
+                None => continue
+            };
+            let pump_on = self.repository.get_last_pin_state(control_name, pump_pin).map_or(false, |state| state.is_on());
+            let run_pump = if self.has_demand(control_name, control_node) {
+                true
+            } else {
+                pump_on && self.get_demand_end(control_name, control_node)
+                    .map_or(false, |dt| *now - dt < Duration::seconds(self.config.pump_overrun_time() as i64))
+            };
+            if run_pump != pump_on {
+                changes.entry(control_name.clone()).or_insert_with(HashMap::new).insert(pump_pin, PinValue::Digital(run_pump));
             }
+        }
+        changes
+    }
+}
 
-            it "should provide zone state"
-            {
-                for (expected, value, temp, hour) in vec![
-                    (true, 1023, 19.0, 8),
-                    (true, 0, 19.0, 8),
-                    (true, 306, 19.8, 8),
-                    (true, 716, 19.3, 8),
-                    (true, 0, 19.25, 8),
-                    (false, 0, 19.4, 8),
-                    (false, 716, 20.0, 8),
-                    (false, 716, 19.0, 9),
-                    (false, 0, 19.0, 9),
-                ] {
-                    assert_eq!(
-                        zone_decider.should_be_on(
-                            &PinState::new(1, PinValue::Analog(value), Local.ymd(2019, 8, 1).and_hms(8, 0, 0), None),
-                            &zone,
-                            &Temperature::new(temp),
-                            &Local.ymd(2019, 8, 1).and_hms(hour, 0, 0)
-                        ),
-                        expected,
-                        "test with {} {}", value, temp
-                    );
+// sent commands are pending until the node reports the same value back,
+// they are resent with doubling delay and an alarm is raised after command_max_attempts
+#[derive(new)]
+pub struct CommandDecider<'a>
+{
+    repository: &'a PinStateRepository<'a>,
+    config: &'a Settings
+}
+
+impl CommandDecider<'_>
+{
+    fn get_retry_delay(&self, attempts: i32) -> Duration
+    {
+        Duration::seconds((self.config.command_retry_time() as i64) << (attempts - 1).max(0).min(16))
+    }
+
+    // failed commands are not sent again until the expected value changes
+    pub fn is_pending(&self, control_name: &str, pin: u8, value: u16) -> bool
+    {
+        self.repository.get_pending_command(control_name, pin)
+            .map_or(false, |command| command.value == value as i32)
+    }
+
+    // sending the pending value again is a retry, other values replace the pending command
+    pub fn save_sent(&self, control_name: &str, pin: u8, value: u16, now: &DateTime<Local>)
+    {
+        let result = match self.repository.get_pending_command(control_name, pin).filter(|command| command.value == value as i32) {
+            Some(command) => self.repository.update_command_retry(&command.id, command.attempts + 1, &(*now + self.get_retry_delay(command.attempts + 1)), now),
+            None => self.repository.save_command(control_name, pin, value, &(*now + self.get_retry_delay(1)), now)
+        };
+        if let Err(e) = result {
+            warn!("Unable to save command for {} pin {} {:?}", control_name, pin, e);
+        }
+    }
+
+    fn is_acknowledged(&self, command: &PendingCommand, pin: u8) -> bool
+    {
+        self.repository.get_last_pin_state(&command.name, pin)
+            .map_or(false, |state| state.value.as_u16() as i32 == command.value && state.dt.naive_local() >= command.dtc)
+    }
+
+    // acknowledged and invalid commands are removed, failed ones are kept with an alarm
+    pub fn get_commands_to_retry(&self, now: &DateTime<Local>) -> Vec<PendingCommand>
+    {
+        let mut commands = Vec::new();
+        for command in self.repository.get_pending_commands() {
+            let pin = match command.get_pin_value() {
+                Some((pin, _)) => pin,
+                None => {
+                    warn!("Dropping invalid command for {} pin {} value {}", command.name, command.pin, command.value);
+                    self.remove(&command);
+                    continue;
                 }
+            };
+            if self.is_acknowledged(&command, pin) {
+                self.remove(&command);
+            } else if command.failed || command.next_retry > now.naive_local() {
+                continue;
+            } else if command.attempts >= self.config.command_max_attempts() as i32 {
+                self.report_failed(&command, now);
+                if let Err(e) = self.repository.set_command_failed(&command.id) {
+                    warn!("Unable to mark command failed for {} pin {} {:?}", command.name, command.pin, e);
+                }
+            } else {
+                commands.push(command);
+            }
+        }
+        commands
+    }
+
+    fn remove(&self, command: &PendingCommand)
+    {
+        if let Err(e) = self.repository.remove_command_by_id(&command.id) {
+            warn!("Unable to remove command for {} pin {} {:?}", command.name, command.pin, e);
+        }
+    }
+
+    fn report_failed(&self, command: &PendingCommand, now: &DateTime<Local>)
+    {
+        let message = format!("Pin {} value {} not acknowledged after {} attempts since {}, last sent {}", command.pin, command.value, command.attempts, command.dtc, command.last_sent);
+        warn!("Node {} command failed: {}", command.name, message);
+        if let Err(e) = self.repository.save_event(&command.name, EVENT_COMMAND_FAILED, &message, now) {
+            warn!("Unable to save command failed event for {} {:?}", command.name, e);
+        }
+    }
+}
+
+// opens zone valves one by one with the node pump running while the heater is idle,
+// runs missed or interrupted by demand are retried at the next idle evaluation
+#[derive(new)]
+pub struct ExerciseDecider<'a>
+{
+    repository: &'a PinStateRepository<'a>,
+    cycle: &'a CycleDecider<'a>,
+    config: &'a Settings
+}
+
+impl ExerciseDecider<'_>
+{
+    // zones in the order they are exercised
+    fn get_zones<'b>(&self, control_nodes: &'b ControlNodes) -> Vec<(&'b String, &'b ControlNode, &'b String, &'b Zone)>
+    {
+        let mut zones: Vec<(&String, &ControlNode, &String, &Zone)> = control_nodes.iter()
+            .flat_map(|(control_name, control_node)| control_node.zones.iter()
+                .map(move |(zone_name, zone)| (control_name, control_node, zone_name, zone))
+            )
+            .collect();
+        zones.sort_by(|a, b| (a.0, a.2).cmp(&(b.0, b.2)));
+        zones
+    }
+
+    // latest exercise_time on an exercise day not after now
+    pub fn get_scheduled_start(&self, now: &DateTime<Local>) -> Option<DateTime<Local>>
+    {
+        (0..8).map(|days| now.naive_local().date() - Duration::days(days))
+            .filter(|date| self.config.is_exercise_day(date))
+            .filter_map(|date| Local.from_local_datetime(&date.and_time(self.config.exercise_time())).single())
+            .find(|start| start <= now)
+    }
+
+    fn get_last_event_dt(&self, event_kind: &str) -> Option<DateTime<Local>>
+    {
+        self.repository.get_last_event(EXERCISE_EVENT_NAME, event_kind)
+            .and_then(|event| Local.from_local_datetime(&event.dtc).single())
+    }
+
+    // start of the run for the latest schedule unless it was interrupted
+    pub fn get_run_start(&self, scheduled: &DateTime<Local>) -> Option<DateTime<Local>>
+    {
+        let started = self.get_last_event_dt(EVENT_EXERCISE_RUN).filter(|started| started >= scheduled)?;
+        if self.get_last_event_dt(EVENT_EXERCISE_MISSED).map_or(false, |missed| missed >= started) {
+            return None;
+        }
+        Some(started)
+    }
+
+    // a run has a slot for every zone and a last one to close everything
+    fn get_run_length(&self, zone_count: usize) -> Duration
+    {
+        Duration::seconds(self.config.exercise_duration() as i64 * (zone_count as i64 + 1))
+    }
+
+    // a run starts only while the heater is idle without demand and continues while there is no demand,
+    // its own open valve moves the heater to zones_closing
+    pub fn get_pins_to_change(&self, control_nodes: &ControlNodes, phase: HeaterPhase, has_demand: bool, now: &DateTime<Local>) -> Option<HashMap<String, HashMap<u8, PinValue>>>
+    {
+        let scheduled = self.get_scheduled_start(now)?;
+        let zones = self.get_zones(control_nodes);
+        let run_length = self.get_run_length(zones.len());
+        let start = match self.get_run_start(&scheduled) {
+            Some(start) if *now >= start + run_length => return None,
+            Some(start) if !has_demand && (phase == HeaterPhase::Idle || phase == HeaterPhase::ZonesClosing) => start,
+            Some(start) => {
+                self.record_missed(&start, "interrupted", now);
+                return None;
+            },
+            None if !self.is_due(&scheduled, run_length, now) => return None,
+            None if !has_demand && phase == HeaterPhase::Idle => {
+                if let Err(e) = self.repository.save_event(EXERCISE_EVENT_NAME, EVENT_EXERCISE_RUN, "started", now) {
+                    warn!("Unable to save exercise run {:?}", e);
+                    return None;
+                }
+                *now
+            },
+            None => {
+                let since = self.get_last_event_dt(EVENT_EXERCISE_RUN).map_or(scheduled, |started| started.max(scheduled));
+                self.record_missed(&since, "postponed", now);
+                return None;
+            }
+        };
+        let slot = ((*now - start).num_seconds() / self.config.exercise_duration().max(1) as i64) as usize;
+        let mut changes: HashMap<String, HashMap<u8, PinValue>> = HashMap::new();
+        for (i, (control_name, _, zone_name, zone)) in zones.iter().enumerate() {
+            let open = i == slot;
+            if let Some(last_state) = self.repository.get_last_pin_state(control_name, zone.control_pin) {
+                if last_state.is_on() == open {
+                    continue;
+                }
+                let value = if open { percent_to_analog(100) } else { 0u16 };
+                changes.entry((*control_name).clone()).or_insert_with(HashMap::new).insert(zone.control_pin, PinValue::Analog(value));
+                if open {
+                    self.record_run(zone_name, &start, now);
+                }
+            }
+        }
+        for (control_name, control_node) in control_nodes {
+            if let Some(pump_pin) = get_pump_pin(self.config, control_name, control_node) {
+                let run_pump = zones.get(slot).map_or(false, |(zone_control_name, _, _, _)| *zone_control_name == control_name);
+                let pump_on = self.repository.get_last_pin_state(control_name, pump_pin).map_or(false, |state| state.is_on());
+                if run_pump != pump_on && self.cycle.can_switch_pump(control_name, pump_pin, run_pump, now) {
+                    changes.entry(control_name.clone()).or_insert_with(HashMap::new).insert(pump_pin, PinValue::Digital(run_pump));
+                }
+            }
+        }
+        Some(changes)
+    }
+
+    // within the scheduled run or missed since it was scheduled
+    fn is_due(&self, scheduled: &DateTime<Local>, run_length: Duration, now: &DateTime<Local>) -> bool
+    {
+        *now < *scheduled + run_length || self.get_last_event_dt(EVENT_EXERCISE_MISSED).map_or(false, |missed| missed >= *scheduled)
+    }
+
+    // once per run that could not start or was interrupted
+    fn record_missed(&self, since: &DateTime<Local>, reason: &str, now: &DateTime<Local>)
+    {
+        if self.get_last_event_dt(EVENT_EXERCISE_MISSED).map_or(false, |missed| missed >= *since) {
+            return;
+        }
+        info!("Exercise run {}, retried when the heater is idle", reason);
+        if let Err(e) = self.repository.save_event(EXERCISE_EVENT_NAME, EVENT_EXERCISE_MISSED, reason, now) {
+            warn!("Unable to save missed exercise run {:?}", e);
+        }
+    }
+
+    // once per zone and run
+    fn record_run(&self, zone_name: &str, start: &DateTime<Local>, now: &DateTime<Local>)
+    {
+        if self.repository.get_last_event(zone_name, EVENT_EXERCISE).map_or(false, |event| event.dtc >= start.naive_local()) {
+            return;
+        }
+        let message = format!("Valve opened for {} secs", self.config.exercise_duration());
+        info!("Exercise zone {}: {}", zone_name, message);
+        if let Err(e) = self.repository.save_event(zone_name, EVENT_EXERCISE, &message, now) {
+            warn!("Unable to save exercise event for {} {:?}", zone_name, e);
+        }
+    }
+}
+
+// hot water tanks are charged regardless of zone schedules and outdoor temperature
+#[derive(new)]
+pub struct HotWaterDecider<'a>
+{
+    repository: &'a PinStateRepository<'a>,
+    config: &'a Settings
+}
+
+impl HotWaterDecider<'_>
+{
+    pub fn get_tank_temperature(&self, tank: &HotWaterZone, now: &DateTime<Local>) -> Option<Temperature>
+    {
+        self.repository.get_average_temperature(&tank.sensor_name, tank.sensor_pin, &(*now - Duration::seconds(self.config.tank_average_time() as i64)))
+    }
+
+    // charging starts hysteresis below target and lasts until target, stops without readings
+    pub fn should_charge(&self, control_name: &str, tank: &HotWaterZone, now: &DateTime<Local>) -> bool
+    {
+        match self.get_tank_temperature(tank, now) {
+            Some(t) => if is_hot_water_charging(self.repository, control_name, tank) {
+                t < tank.target_temperature
+            } else {
+                t.value < tank.target_temperature.value - tank.hysteresis
+            },
+            None => false
+        }
+    }
+
+    pub fn should_any_charge(&self, control_nodes: &ControlNodes, now: &DateTime<Local>) -> bool
+    {
+        control_nodes.iter().any(|(control_name, control_node)| control_node.hot_water.as_ref()
+            .map_or(false, |tank| self.should_charge(control_name, tank, now))
+        )
+    }
+
+    // zones wait while a priority tank is charging
+    pub fn is_priority_charging(&self, control_nodes: &ControlNodes, now: &DateTime<Local>) -> bool
+    {
+        control_nodes.iter().any(|(control_name, control_node)| control_node.hot_water.as_ref()
+            .map_or(false, |tank| tank.priority && self.should_charge(control_name, tank, now))
+        )
+    }
+
+    pub fn get_pins_to_change(&self, control_nodes: &ControlNodes, now: &DateTime<Local>) -> HashMap<String, HashMap<u8, PinValue>>
+    {
+        let mut changes: HashMap<String, HashMap<u8, PinValue>> = HashMap::new();
+        for (control_name, control_node) in control_nodes {
+            if let Some(tank) = &control_node.hot_water {
+                let charge = self.should_charge(control_name, tank, now);
+                if charge != is_hot_water_charging(self.repository, control_name, tank) {
+                    changes.entry(control_name.clone()).or_insert_with(HashMap::new).insert(tank.control_pin, PinValue::Digital(charge));
+                }
+            }
+        }
+        changes
+    }
+}
+
+// heater lifecycle, the next phase follows from the saved one and the current inputs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeaterPhase
+{
+    // no demand, heater and valves closed
+    Idle,
+    // demand without any open valve yet
+    OpeningValves,
+    // valves open, heater waits for acctuator_warmup_time
+    WarmingActuators,
+    // any heat source on
+    Firing,
+    // heater off, zones kept as they are for heater_pump_stop_time
+    PumpOverrun,
+    // heater off without demand while valves are still open
+    ZonesClosing
+}
+
+impl HeaterPhase
+{
+    pub fn name(&self) -> &'static str
+    {
+        match self {
+            HeaterPhase::Idle => "idle",
+            HeaterPhase::OpeningValves => "opening_valves",
+            HeaterPhase::WarmingActuators => "warming_actuators",
+            HeaterPhase::Firing => "firing",
+            HeaterPhase::PumpOverrun => "pump_overrun",
+            HeaterPhase::ZonesClosing => "zones_closing"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HeaterPhase>
+    {
+        match name {
+            "idle" => Some(HeaterPhase::Idle),
+            "opening_valves" => Some(HeaterPhase::OpeningValves),
+            "warming_actuators" => Some(HeaterPhase::WarmingActuators),
+            "firing" => Some(HeaterPhase::Firing),
+            "pump_overrun" => Some(HeaterPhase::PumpOverrun),
+            "zones_closing" => Some(HeaterPhase::ZonesClosing),
+            _ => None
+        }
+    }
+
+    // the heater is only turned on after warming actuators and always turned off into pump overrun
+    pub fn can_change_to(self, next: HeaterPhase) -> bool
+    {
+        match (self, next) {
+            (from, to) if from == to => true,
+            (HeaterPhase::Firing, HeaterPhase::PumpOverrun) => true,
+            (HeaterPhase::Firing, _) => false,
+            (HeaterPhase::WarmingActuators, HeaterPhase::Firing) => true,
+            (_, HeaterPhase::Firing) | (_, HeaterPhase::PumpOverrun) => false,
+            _ => true
+        }
+    }
+
+    pub fn next(self, inputs: &HeaterInputs) -> HeaterPhase
+    {
+        let waiting = match (inputs.has_demand, inputs.any_valve_open) {
+            (true, true) => HeaterPhase::WarmingActuators,
+            (true, false) => HeaterPhase::OpeningValves,
+            (false, true) => HeaterPhase::ZonesClosing,
+            (false, false) => HeaterPhase::Idle
+        };
+        match self {
+            HeaterPhase::Firing if inputs.heater_on => HeaterPhase::Firing,
+            HeaterPhase::Firing => HeaterPhase::PumpOverrun,
+            HeaterPhase::PumpOverrun if !inputs.overrun_done => HeaterPhase::PumpOverrun,
+            HeaterPhase::WarmingActuators if inputs.has_demand && inputs.heater_on => HeaterPhase::Firing,
+            _ => waiting
+        }
+    }
+}
+
+// everything a phase transition depends on, read once per evaluation
+#[derive(Debug, new, Clone, Copy, PartialEq)]
+pub struct HeaterInputs
+{
+    // any zone or tank wants heat
+    pub has_demand: bool,
+    pub any_valve_open: bool,
+    pub heater_on: bool,
+    // no pump overrun in progress, the heater is on or has been off for heater_pump_stop_time
+    pub overrun_done: bool
+}
+
+#[derive(new)]
+pub struct HeaterDecider<'a>
+{
+    repository: &'a PinStateRepository<'a>,
+    config: &'a Settings
+}
+
+impl HeaterDecider<'_>
+{
+    // demand is any zone or tank wanting heat
+    pub fn get_inputs(&self, nodes: &ControlNodes, has_demand: bool, now: &DateTime<Local>) -> HeaterInputs
+    {
+        let state = self.get_state();
+        let any_valve_open = nodes.iter().any(|(control_name, control_node)| {
+            control_node.zones.values().any(|zone| self.repository.get_last_pin_state(control_name, zone.control_pin).map_or(false, |state| state.is_on()))
+                || control_node.hot_water.as_ref().map_or(false, |tank| is_hot_water_charging(self.repository, control_name, tank))
+        });
+        HeaterInputs::new(
+            has_demand,
+            any_valve_open,
+            state.as_ref().map_or(false, |state| state.is_on()),
+            state.as_ref().map_or(true, |state| state.is_on() || self.can_turn_zones_off(state, now))
+        )
+    }
+
+    // without a saved phase, e.g. on first start, the phase is taken from the pins as they are
+    fn get_initial_phase(inputs: &HeaterInputs) -> HeaterPhase
+    {
+        if inputs.heater_on {
+            return HeaterPhase::Firing;
+        }
+        if !inputs.overrun_done {
+            return HeaterPhase::PumpOverrun;
+        }
+        HeaterPhase::Idle.next(inputs)
+    }
+
+    // loads the saved phase, moves it by the inputs and saves the result
+    pub fn update_phase(&self, nodes: &ControlNodes, has_demand: bool, now: &DateTime<Local>) -> HeaterPhase
+    {
+        let inputs = self.get_inputs(nodes, has_demand, now);
+        let current = self.get_saved_phase().map(|(phase, _)| phase);
+        let phase = match current {
+            Some(current) => current.next(&inputs),
+            None => HeaterDecider::get_initial_phase(&inputs)
+        };
+        if self.save_phase(phase, now) {
+            return phase;
+        }
+        current.unwrap_or(phase)
+    }
+
+    // last persisted phase and when it was entered
+    pub fn get_saved_phase(&self) -> Option<(HeaterPhase, DateTime<Local>)>
+    {
+        let event = self.repository.get_last_event(HEATER_EVENT_NAME, EVENT_HEATER_PHASE)?;
+        let phase = HeaterPhase::from_name(&event.message)?;
+        Local.from_local_datetime(&event.dtc).single().map(|dt| (phase, dt))
+    }
+
+    // transitions are logged and saved to the events table, illegal ones are refused
+    pub fn save_phase(&self, phase: HeaterPhase, now: &DateTime<Local>) -> bool
+    {
+        let previous = self.get_saved_phase();
+        if previous.map_or(false, |(saved, _)| saved == phase) {
+            return true;
+        }
+        if let Some((saved, _)) = previous.filter(|(saved, _)| !saved.can_change_to(phase)) {
+            warn!("Heater can not change from {} to {}", saved.name(), phase.name());
+            return false;
+        }
+        match previous {
+            Some((saved, since)) => info!("Heater {} -> {} after {} secs", saved.name(), phase.name(), (*now - since).num_seconds()),
+            None => info!("Heater {}", phase.name())
+        }
+        if let Err(e) = self.repository.save_event(HEATER_EVENT_NAME, EVENT_HEATER_PHASE, phase.name(), now) {
+            warn!("Unable to save heater phase {:?}", e);
+            return false;
+        }
+        true
+    }
+
+    pub fn should_be_on(&self, nodes: &ControlNodes, now: &DateTime<Local>) -> bool
+    {
+        let hot_water_charging = nodes.iter().any(|(control_name, control_node)| control_node.hot_water.as_ref()
+            .map_or(false, |tank| is_hot_water_charging(self.repository, control_name, tank))
+        );
+        if hot_water_charging {
+            return true;
+        }
+        // overridden zones are heated even when warm outside
+        let any_override = nodes.values().flat_map(|node| node.zones.keys()).any(|name| self.repository.get_active_override(name, now).is_some());
+        if !any_override && is_above_outdoor_cutoff(self.config, &get_outdoor_temperature(self.repository, self.config, now)) {
+            return false;
+        }
+        if let Some(first_zone_on) = self.repository.get_first_zone_on_dt(nodes, &(*now - Duration::hours(24))) {
+            return *now - first_zone_on > Duration::seconds(self.config.acctuator_warmup_time() as i64);
+        }
+        false
+    }
+
+    pub fn can_turn_zones_off(&self, state: &PinState, now: &DateTime<Local>) -> bool
+    {
+        !state.is_on() && *now - state.dt > Duration::seconds(self.config.heater_pump_stop_time() as i64)
+    }
+
+    pub fn get_state(&self) -> Option<PinState>
+    {
+        get_heater_state(self.repository, self.config)
+    }
+
+    // enabled stages join one by one every heat_source_stage_delay secs while the heater runs
+    // disabled sources are turned off, sources without any recorded state are skipped
+    pub fn get_sources_to_change(&self, turn_on: bool, now: &DateTime<Local>) -> HashMap<String, HashMap<u8, PinValue>>
+    {
+        let outdoor_temperature = get_outdoor_temperature(self.repository, self.config, now);
+        let time = now.naive_local().time();
+        let sources: Vec<(HeatSource, bool)> = self.config.heat_sources().into_iter()
+            .map(|source| {
+                let enabled = turn_on && source.is_enabled(&outdoor_temperature, &time);
+                (source, enabled)
+            })
+            .collect();
+        let mut enabled_stages: Vec<u8> = sources.iter().filter(|(_, enabled)| *enabled).map(|(source, _)| source.stage).collect();
+        enabled_stages.sort();
+        enabled_stages.dedup();
+        let running_stages = match get_heater_state(self.repository, self.config) {
+            Some(ref state) if state.is_on() && self.config.heat_source_stage_delay() > 0 =>
+                1 + ((*now - state.dt).num_seconds() / self.config.heat_source_stage_delay() as i64) as usize,
+            Some(ref state) if state.is_on() => enabled_stages.len(),
+            _ => 1
+        };
+        enabled_stages.truncate(running_stages);
+
+        let mut changes: HashMap<String, HashMap<u8, PinValue>> = HashMap::new();
+        for (source, enabled) in sources {
+            let on = enabled && enabled_stages.contains(&source.stage);
+            // sources never reported by their node are not switched
+            let is_on = match self.repository.get_last_pin_state(&source.control_name, source.control_pin) {
+                Some(state) => state.is_on(),
+                None => continue
+            };
+            if on != is_on {
+                changes.entry(source.control_name.clone()).or_insert_with(HashMap::new).insert(source.control_pin, source.get_value(on));
+            }
+        }
+        changes
+    }
+}
+
+
+#[cfg(test)]
+mod test_deciders
+{
+    use super::*;
+    use chrono::{TimeZone, NaiveTime};
+    use crate::repository::test_repository::{create_nodes, create_repository, create_zone as create_test_zone, at, save_pin, save_temperature};
+    use crate::zone::{Interval};
+    use crate::config::{Config};
+    use crate::config::tests::{create_settings, create_fixture};
+
+    fn create_zone() -> (Zone, Settings)
+    {
+        let config = Settings::new(Config::new(String::from("test"), String::from("host"), String::from("main"), 3));
+        let intervals = vec![
+            Interval::new(NaiveTime::from_hms(8, 0, 0), NaiveTime::from_hms(9, 0, 0), Temperature::new(20.0)),
+            Interval::new(NaiveTime::from_hms(23, 1, 0), NaiveTime::from_hms(23, 3, 3), Temperature::new(30.5))
+        ];
+        (Zone::new(String::from("zone1"), 1, intervals, 2), config)
+    }
+
+    speculate! {
+        describe "zone temperature"
+        {
+            before
+            {
+                let (connection, _) = create_fixture("");
+                let repository = PinStateRepository::new(&connection);
+                let (zone, config) = create_zone();
+                let decider = TemperatureStateDecider::new(&repository, &config);
+            }
+
+            it "should match value"
+            {
+                for (expected, temp, hour) in vec![
+                    (1023, 19.0, 8),
+                    (306, 19.8, 8),
+                    (716, 19.3, 8),
+                    (1023, 2.3, 8),
+                    (0, 20.0, 8),
+                    (0, 20.0, 9),
+                ] {
+                    assert_eq!(
+                        decider.get_expected_value(
+                            &Temperature::new(temp), &zone, &Local.ymd(2019, 8, 1).and_hms(hour, 0, 0)
+                        ).as_u16(),
+                        expected
+                    );
+                }
+            }
+        }
+
+        describe "setback temperature"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("setback_temperature: 17.0\nfrost_protection_temperature: 7.0");
+                let repository = PinStateRepository::new(&connection);
+                let (mut zone, _) = create_zone();
+                let decider = TemperatureStateDecider::new(&repository, &config);
+            }
+
+            it "should use schedule when interval matches"
+            {
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)), Temperature::new(20.0));
+            }
+
+            it "should fall back to general setback temperature"
+            {
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(9, 0, 0)), Temperature::new(17.0));
+            }
+
+            it "should prefer zone setback temperature"
+            {
+                zone.setback_temperature = Some(Temperature::new(15.5));
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(9, 0, 0)), Temperature::new(15.5));
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 30, 0)), Temperature::new(20.0));
+            }
+
+            it "should never go below frost protection"
+            {
+                zone.setback_temperature = Some(Temperature::new(3.0));
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(9, 0, 0)), Temperature::new(7.0));
+
+                let (zone, _) = create_zone();
+                let config = create_settings("frost_protection_temperature: 21.0");
+                let decider = TemperatureStateDecider::new(&repository, &config);
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)), Temperature::new(21.0));
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(9, 0, 0)), Temperature::new(21.0));
+            }
+
+            it "should turn zone on below setback"
+            {
+                let zone_decider = ZoneStateDecider::new(&repository, &decider, &config);
+                let off_state = PinState::new(1, PinValue::Analog(0), Local.ymd(2019, 8, 1).and_hms(8, 0, 0), None);
+                let now = Local.ymd(2019, 8, 1).and_hms(12, 0, 0);
+                assert!(zone_decider.should_be_on(&off_state, &zone, &Temperature::new(16.0), &now));
+                assert!(!zone_decider.should_be_on(&off_state, &zone, &Temperature::new(16.5), &now));
+                assert_eq!(zone_decider.get_value_to_change_to(&off_state, &zone, &Temperature::new(15.0), &now), Some(PinValue::Analog(1023)));
+            }
+        }
+
+        describe "away temperature"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("away_start: 2019-08-02\naway_end: 2019-08-04\naway_temperature: 15.0\nsetback_temperature: 17.0");
+                let repository = PinStateRepository::new(&connection);
+                let (zone, _) = create_zone();
+                let decider = TemperatureStateDecider::new(&repository, &config);
+            }
+
+            it "should follow away temperature within period"
+            {
+                for (expected, day, hour) in vec![
+                    (20.0, 1, 8),
+                    (17.0, 1, 12),
+                    (15.0, 2, 0),
+                    (15.0, 2, 8),
+                    (15.0, 4, 8),
+                    (15.0, 4, 23),
+                    (20.0, 5, 8),
+                    (17.0, 5, 12),
+                ] {
+                    assert_eq!(
+                        decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, day).and_hms(hour, 0, 0)),
+                        Temperature::new(expected),
+                        "day {} hour {}", day, hour
+                    );
+                }
+            }
+        }
+
+        describe "override temperature"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("away_start: 2019-08-01\naway_end: 2019-08-04\naway_temperature: 15.0\nfrost_protection_temperature: 7.0");
+                let repository = PinStateRepository::new(&connection);
+                let (zone, _) = create_zone();
+                let decider = TemperatureStateDecider::new(&repository, &config);
+            }
+
+            it "should prefer override until it expires"
+            {
+                repository.save_override("zone1", 23.0, &Local.ymd(2019, 8, 1).and_hms(10, 0, 0), &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)).unwrap();
+                repository.save_override("zone2", 5.0, &Local.ymd(2019, 8, 1).and_hms(10, 0, 0), &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)).unwrap();
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(7, 0, 0)), Temperature::new(15.0));
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)), Temperature::new(23.0));
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(9, 59, 0)), Temperature::new(23.0));
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(10, 0, 0)), Temperature::new(15.0));
+
+                let other_zone = Zone::new(String::from("zone2"), 1, vec![], 3);
+                assert_eq!(decider.get_expected_temperature(&other_zone, &Local.ymd(2019, 8, 1).and_hms(9, 0, 0)), Temperature::new(7.0));
+            }
+        }
+
+        describe "constant temperature"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("constant_temperature_enabled: true\nconstant_temperature_expected: 18.5\nsetback_temperature: 16.0");
+                let repository = PinStateRepository::new(&connection);
+                let (zone, _) = create_zone();
+                let decider = TemperatureStateDecider::new(&repository, &config);
+                let zone_decider = ZoneStateDecider::new(&repository, &decider, &config);
+            }
+
+            it "should ignore zone times"
+            {
+                for hour in vec![0, 8, 9, 23] {
+                    assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(hour, 2, 0)), Temperature::new(18.5), "hour {}", hour);
+                }
+            }
+
+            it "should follow schedule when disabled"
+            {
+                let config = create_settings("constant_temperature_enabled: false\nconstant_temperature_expected: 18.5\nsetback_temperature: 16.0");
+                let decider = TemperatureStateDecider::new(&repository, &config);
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)), Temperature::new(20.0));
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(12, 0, 0)), Temperature::new(16.0));
+            }
+
+            it "should give way to override and away"
+            {
+                repository.save_override("zone1", 22.0, &Local.ymd(2019, 8, 1).and_hms(10, 0, 0), &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)).unwrap();
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(9, 0, 0)), Temperature::new(22.0));
+
+                let config = create_settings("constant_temperature_enabled: true\nconstant_temperature_expected: 18.5\naway_start: 2019-08-02\naway_end: 2019-08-03\naway_temperature: 15.0");
+                let decider = TemperatureStateDecider::new(&repository, &config);
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 2).and_hms(9, 0, 0)), Temperature::new(15.0));
+            }
+
+            it "should switch zones using constant temperature"
+            {
+                let now = Local.ymd(2019, 8, 1).and_hms(12, 0, 0);
+                let off_state = PinState::new(1, PinValue::Analog(0), Local.ymd(2019, 8, 1).and_hms(8, 0, 0), None);
+                let on_state = PinState::new(1, PinValue::Analog(1023), Local.ymd(2019, 8, 1).and_hms(8, 0, 0), None);
+                assert!(zone_decider.should_be_on(&off_state, &zone, &Temperature::new(17.5), &now));
+                assert!(!zone_decider.should_be_on(&off_state, &zone, &Temperature::new(18.0), &now));
+                assert!(zone_decider.should_be_on(&on_state, &zone, &Temperature::new(18.4), &now));
+                assert_eq!(zone_decider.get_value_to_change_to(&on_state, &zone, &Temperature::new(18.5), &now), Some(PinValue::Analog(0)));
+            }
+        }
+
+        describe "weather compensation"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("outdoor_sensor_name: outside\noutdoor_sensor_pin: 5\nheating_curve_slope: 0.2\nheating_curve_reference: 10.0\nheating_curve_max_offset: 3.0\noutdoor_cutoff_temperature: 17.0\nsetback_temperature: 16.0");
+                let repository = PinStateRepository::new(&connection);
+                let (zone, _) = create_zone();
+                let decider = TemperatureStateDecider::new(&repository, &config);
+            }
+
+            it "should map outdoor temperature to offset"
+            {
+                for (expected, outdoor) in vec![
+                    (0.0, 15.0),
+                    (0.0, 10.0),
+                    (1.0, 5.0),
+                    (2.0, 0.0),
+                    (3.0, -5.0),
+                    (3.0, -20.0),
+                ] {
+                    assert_eq!(decider.get_heating_curve_offset(&Some(Temperature::new(outdoor))), expected, "outdoor {}", outdoor);
+                }
+                assert_eq!(decider.get_heating_curve_offset(&None), 0.0);
+            }
+
+            it "should not change targets without outdoor readings"
+            {
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)), Temperature::new(20.0));
+                save_temperature(&repository, "outside", 5, 0.0, Local.ymd(2019, 8, 1).and_hms(7, 0, 0));
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)), Temperature::new(20.0));
+            }
+
+            it "should raise targets when cold outside"
+            {
+                save_temperature(&repository, "outside", 5, 0.0, Local.ymd(2019, 8, 1).and_hms(7, 50, 0));
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)), Temperature::new(22.0));
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(7, 55, 0)), Temperature::new(18.0));
+            }
+
+            it "should not raise overrides"
+            {
+                save_temperature(&repository, "outside", 5, 0.0, Local.ymd(2019, 8, 1).and_hms(7, 50, 0));
+                repository.save_override("zone1", 21.0, &Local.ymd(2019, 8, 1).and_hms(10, 0, 0), &Local.ymd(2019, 8, 1).and_hms(7, 0, 0)).unwrap();
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)), Temperature::new(21.0));
+            }
+
+            it "should keep only frost protection when warm outside"
+            {
+                save_temperature(&repository, "outside", 5, 18.0, Local.ymd(2019, 8, 1).and_hms(7, 50, 0));
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)), Temperature::new(5.0));
+            }
+
+            it "should keep overrides when warm outside"
+            {
+                save_temperature(&repository, "outside", 5, 18.0, Local.ymd(2019, 8, 1).and_hms(7, 50, 0));
+                repository.save_override("zone1", 21.0, &Local.ymd(2019, 8, 1).and_hms(10, 0, 0), &Local.ymd(2019, 8, 1).and_hms(7, 0, 0)).unwrap();
+                assert_eq!(decider.get_expected_temperature(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)), Temperature::new(21.0));
+            }
+
+            it "should not raise away and constant temperatures"
+            {
+                save_temperature(&repository, "outside", 5, 0.0, Local.ymd(2019, 8, 1).and_hms(7, 50, 0));
+                let now = Local.ymd(2019, 8, 1).and_hms(8, 0, 0);
+                let config = create_settings("outdoor_sensor_name: outside\noutdoor_sensor_pin: 5\nheating_curve_slope: 0.2\nheating_curve_reference: 10.0\naway_start: 2019-08-01\naway_end: 2019-08-02\naway_temperature: 15.0");
+                let decider = TemperatureStateDecider::new(&repository, &config);
+                assert_eq!(decider.get_expected_temperature(&zone, &now), Temperature::new(15.0));
+                let config = create_settings("outdoor_sensor_name: outside\noutdoor_sensor_pin: 5\nheating_curve_slope: 0.2\nheating_curve_reference: 10.0\nconstant_temperature_enabled: true\nconstant_temperature_expected: 18.5");
+                let decider = TemperatureStateDecider::new(&repository, &config);
+                assert_eq!(decider.get_expected_temperature(&zone, &now), Temperature::new(18.5));
+            }
+
+            it "should suppress heater when warm outside"
+            {
+                create_repository(&connection);
+                let config = create_settings("outdoor_sensor_name: outside\noutdoor_sensor_pin: 5\noutdoor_cutoff_temperature: 17.0");
+                let heater_decider = HeaterDecider::new(&repository, &config);
+                let nodes = create_nodes();
+                assert!(heater_decider.should_be_on(&nodes, &Local.ymd(2019, 8, 3).and_hms(7, 0, 0)));
+                save_temperature(&repository, "outside", 5, 17.5, Local.ymd(2019, 8, 3).and_hms(6, 50, 0));
+                assert!(!heater_decider.should_be_on(&nodes, &Local.ymd(2019, 8, 3).and_hms(7, 0, 0)));
+                repository.save_override("zone1", 22.0, &Local.ymd(2019, 8, 3).and_hms(10, 0, 0), &Local.ymd(2019, 8, 3).and_hms(6, 0, 0)).unwrap();
+                assert!(heater_decider.should_be_on(&nodes, &Local.ymd(2019, 8, 3).and_hms(7, 0, 0)));
+                repository.remove_override("zone1").unwrap();
+                save_temperature(&repository, "outside", 5, 14.5, Local.ymd(2019, 8, 3).and_hms(6, 55, 0));
+                assert!(heater_decider.should_be_on(&nodes, &Local.ymd(2019, 8, 3).and_hms(7, 0, 0)));
+            }
+        }
+
+        describe "zone states"
+        {
+            before
+            {
+                let (connection, _) = create_fixture("");
+                let repository = PinStateRepository::new(&connection);
+                let (zone, config) = create_zone();
+                let temp_decider = TemperatureStateDecider::new(&repository, &config);
+                let zone_decider = ZoneStateDecider::new(&repository, &temp_decider, &config);
+            }
+
+            it "should provide zone state"
+            {
+                for (expected, value, temp, hour) in vec![
+                    (true, 1023, 19.0, 8),
+                    (true, 0, 19.0, 8),
+                    (true, 306, 19.8, 8),
+                    (true, 716, 19.3, 8),
+                    (true, 0, 19.25, 8),
+                    (false, 0, 19.4, 8),
+                    (false, 716, 20.0, 8),
+                    (false, 716, 19.0, 9),
+                    (false, 0, 19.0, 9),
+                ] {
+                    assert_eq!(
+                        zone_decider.should_be_on(
+                            &PinState::new(1, PinValue::Analog(value), Local.ymd(2019, 8, 1).and_hms(8, 0, 0), None),
+                            &zone,
+                            &Temperature::new(temp),
+                            &Local.ymd(2019, 8, 1).and_hms(hour, 0, 0)
+                        ),
+                        expected,
+                        "test with {} {}", value, temp
+                    );
+                }
+            }
+        }
+
+        describe "window open"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("window_open_drop_rate: 0.2\nwindow_open_detection_time: 600\nwindow_open_suspend_time: 1800");
+                let repository = PinStateRepository::new(&connection);
+                let (zone, _) = create_zone();
+                let decider = WindowOpenDecider::new(&repository, &config);
+            }
+
+            it "should calculate fall rate"
+            {
+                assert!(decider.get_fall_rate(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)).is_none());
+                save_temperature(&repository, "zone1", 1, 20.0, Local.ymd(2019, 8, 1).and_hms(7, 52, 0));
+                assert!(decider.get_fall_rate(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)).is_none());
+                save_temperature(&repository, "zone1", 1, 20.2, Local.ymd(2019, 8, 1).and_hms(7, 54, 0));
+                save_temperature(&repository, "zone1", 1, 19.4, Local.ymd(2019, 8, 1).and_hms(7, 58, 0));
+                let rate = decider.get_fall_rate(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)).expect("rate");
+                assert!((rate - 0.1143).abs() < 0.001, "{}", rate);
+            }
+
+            it "should ignore a single step"
+            {
+                save_temperature(&repository, "zone1", 1, 20.0, Local.ymd(2019, 8, 1).and_hms(7, 59, 50));
+                save_temperature(&repository, "zone1", 1, 19.5, Local.ymd(2019, 8, 1).and_hms(8, 0, 0));
+                assert!(decider.get_fall_rate(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)).is_none());
+                assert!(!decider.is_window_open(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)));
+            }
+
+            it "should ignore slow falls"
+            {
+                save_temperature(&repository, "zone1", 1, 20.0, Local.ymd(2019, 8, 1).and_hms(7, 52, 0));
+                save_temperature(&repository, "zone1", 1, 19.5, Local.ymd(2019, 8, 1).and_hms(7, 58, 0));
+                assert!(!decider.is_window_open(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)));
+                decider.record_suspension(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0));
+                assert!(repository.get_last_event("zone1", EVENT_WINDOW_OPEN).is_none());
+            }
+
+            it "should suspend zone after fast fall"
+            {
+                save_temperature(&repository, "zone1", 1, 20.0, Local.ymd(2019, 8, 1).and_hms(7, 54, 0));
+                save_temperature(&repository, "zone1", 1, 19.2, Local.ymd(2019, 8, 1).and_hms(7, 57, 0));
+                save_temperature(&repository, "zone1", 1, 18.5, Local.ymd(2019, 8, 1).and_hms(7, 59, 0));
+                assert!(decider.is_window_open(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)));
+                assert!(repository.get_last_event("zone1", EVENT_WINDOW_OPEN).is_none());
+                decider.record_suspension(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0));
+                decider.record_suspension(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 30));
+                let event = repository.get_last_event("zone1", EVENT_WINDOW_OPEN).expect("suspension");
+                assert_eq!(event.dtc, Local.ymd(2019, 8, 1).and_hms(8, 0, 0).naive_local());
+                assert_eq!(decider.get_suspended_until(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 10, 0)), Some(Local.ymd(2019, 8, 1).and_hms(8, 30, 0)));
+
+                save_temperature(&repository, "zone1", 1, 18.6, Local.ymd(2019, 8, 1).and_hms(8, 20, 0));
+                assert!(decider.is_window_open(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 29, 59)));
+                assert!(!decider.is_window_open(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 30, 0)));
+                assert!(decider.get_suspended_until(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 30, 0)).is_none());
+            }
+
+            it "should not detect when disabled"
+            {
+                let config = create_settings("");
+                let decider = WindowOpenDecider::new(&repository, &config);
+                save_temperature(&repository, "zone1", 1, 20.0, Local.ymd(2019, 8, 1).and_hms(7, 55, 0));
+                save_temperature(&repository, "zone1", 1, 18.5, Local.ymd(2019, 8, 1).and_hms(7, 58, 0));
+                assert!(!decider.is_window_open(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)));
+            }
+        }
+
+        describe "stale sensor"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("sensor_stale_timeout: 900\nfail_safe: {mode: duty_cycle, on_time: 600, cycle: 1800}");
+                let repository = PinStateRepository::new(&connection);
+                let (zone, _) = create_zone();
+                let decider = StaleSensorDecider::new(&repository, &config);
+            }
+
+            it "should be stale after timeout"
+            {
+                assert!(!decider.is_stale(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)));
+                assert!(decider.is_unreported(&zone));
+                save_temperature(&repository, "zone1", 1, 20.0, Local.ymd(2019, 8, 1).and_hms(8, 0, 0));
+                assert!(!decider.is_stale(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 15, 0)));
+                assert!(decider.is_stale(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 15, 1)));
+                assert!(!decider.is_unreported(&zone));
+
+                decider.report_stale(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 16, 0));
+                decider.report_stale(&zone, &Local.ymd(2019, 8, 1).and_hms(8, 17, 0));
+                let event = repository.get_last_event("zone1", EVENT_SENSOR_STALE).expect("alarm");
+                assert_eq!(event.dtc, Local.ymd(2019, 8, 1).and_hms(8, 16, 0).naive_local());
+            }
+
+            it "should cycle fail safe output"
+            {
+                let cycle_start = Local.timestamp(1800 * 900_000, 0);
+                assert_eq!(decider.get_fail_safe_value(&zone, &cycle_start), PinValue::Analog(percent_to_analog(100)));
+                assert_eq!(decider.get_fail_safe_value(&zone, &(cycle_start + Duration::seconds(599))), PinValue::Analog(percent_to_analog(100)));
+                assert_eq!(decider.get_fail_safe_value(&zone, &(cycle_start + Duration::seconds(600))), PinValue::Analog(0));
+                let mut zone = zone;
+                zone.fail_safe = Some(FailSafe::Off);
+                assert_eq!(decider.get_fail_safe_value(&zone, &cycle_start), PinValue::Analog(0));
+            }
+
+            it "should list zones with unreported sensors"
+            {
+                let nodes = create_nodes();
+                save_temperature(&repository, "zone1", 4, 20.0, Local.ymd(2019, 8, 1).and_hms(8, 0, 0));
+                let mut unreported: Vec<&str> = decider.get_unreported_zones(&nodes).iter().map(|zone| zone.name.as_str()).collect();
+                unreported.sort();
+                assert_eq!(unreported, vec!["zone2", "zone4"]);
+            }
+        }
+
+        describe "short cycling"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("zone_min_on_time: 300\nzone_min_off_time: 600\nheater_min_on_time: 600\nheater_min_off_time: 300\nheater_max_starts_per_hour: 2");
+                let repository = PinStateRepository::new(&connection);
+                let (zone, _) = create_zone();
+                let decider = CycleDecider::new(&repository, &config);
+            }
+
+            it "should keep zone state for minimum time"
+            {
+                assert!(decider.can_switch_zone("main", &zone, true, &at(0)));
+                save_pin(&repository, "main", 2, PinValue::Analog(1023), at(0));
+                assert!(!decider.can_switch_zone("main", &zone, false, &at(3)));
+                assert!(!decider.can_switch_zone("main", &zone, false, &at(4)));
+                assert!(repository.get_last_event("zone1", EVENT_SWITCH_BLOCKED).expect("reported").message.contains("on since"));
+                assert!(decider.can_switch_zone("main", &zone, false, &at(5)));
+
+                save_pin(&repository, "main", 2, PinValue::Analog(0), at(5));
+                assert!(!decider.can_switch_zone("main", &zone, true, &at(10)));
+                assert!(decider.can_switch_zone("main", &zone, true, &at(15)));
+            }
+
+            it "should keep heater state for minimum time"
+            {
+                save_pin(&repository, "main", 34, PinValue::Digital(true), at(0));
+                assert!(!decider.can_switch_heater(false, &at(5)));
+                assert!(decider.can_switch_heater(false, &at(10)));
+                save_pin(&repository, "main", 34, PinValue::Digital(false), at(15));
+                assert!(!decider.can_switch_heater(true, &at(18)));
+                assert!(decider.can_switch_heater(true, &at(20)));
+            }
+
+            it "should limit heater starts per hour"
+            {
+                save_pin(&repository, "main", 34, PinValue::Digital(true), at(0));
+                save_pin(&repository, "main", 34, PinValue::Digital(false), at(15));
+                save_pin(&repository, "main", 34, PinValue::Digital(true), at(25));
+                save_pin(&repository, "main", 34, PinValue::Digital(false), at(40));
+                assert!(!decider.can_switch_heater(true, &at(50)));
+                assert!(repository.get_last_event(HEATER_EVENT_NAME, EVENT_SWITCH_BLOCKED).expect("reported").message.contains("2 times"));
+                assert!(decider.can_switch_heater(true, &at(61)));
+            }
+
+            it "should allow switching without limits"
+            {
+                let config = create_settings("");
+                let decider = CycleDecider::new(&repository, &config);
+                save_pin(&repository, "main", 2, PinValue::Analog(1023), at(0));
+                save_pin(&repository, "main", 34, PinValue::Digital(true), at(0));
+                assert!(decider.can_switch_zone("main", &zone, false, &at(0)));
+                assert!(decider.can_switch_heater(false, &at(0)));
+            }
+        }
+
+        describe "node pump"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("pump_overrun_time: 300");
+                let repository = PinStateRepository::new(&connection);
+                let decider = PumpDecider::new(&repository, &config);
+                let nodes: ControlNodes = map!{
+                    "main".to_owned() => ControlNode::new("main".to_owned(), 30, map!{
+                        "zone1".to_owned() => create_test_zone(1),
+                        "zone2".to_owned() => create_test_zone(2)
+                    })
+                };
+            }
+
+            it "should run while zones are open"
+            {
+                assert_eq!(decider.get_pins_to_change(&nodes, &at(0)), HashMap::new());
+                save_pin(&repository, "main", 1, PinValue::Analog(0), at(0));
+                save_pin(&repository, "main", 2, PinValue::Analog(percent_to_analog(50)), at(1));
+                assert_eq!(decider.get_pins_to_change(&nodes, &at(1)), map!{ "main".to_owned() => map!{ 30 => PinValue::Digital(true) } });
+                save_pin(&repository, "main", 30, PinValue::Digital(true), at(1));
+                assert_eq!(decider.get_pins_to_change(&nodes, &at(5)), HashMap::new());
+            }
+
+            it "should overrun after zones close"
+            {
+                save_pin(&repository, "main", 1, PinValue::Analog(percent_to_analog(100)), at(0));
+                save_pin(&repository, "main", 30, PinValue::Digital(true), at(0));
+                save_pin(&repository, "main", 1, PinValue::Analog(0), at(10));
+                assert_eq!(decider.get_demand_end("main", &nodes["main"]), Some(at(10)));
+                assert_eq!(decider.get_pins_to_change(&nodes, &at(14)), HashMap::new());
+                assert_eq!(decider.get_pins_to_change(&nodes, &at(15)), map!{ "main".to_owned() => map!{ 30 => PinValue::Digital(false) } });
+                save_pin(&repository, "main", 30, PinValue::Digital(false), at(15));
+                assert_eq!(decider.get_pins_to_change(&nodes, &at(20)), HashMap::new());
+            }
+        }
+
+        describe "heater phase"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("heater_pump_stop_time: 600");
+                let repository = PinStateRepository::new(&connection);
+                let decider = HeaterDecider::new(&repository, &config);
+                let nodes = create_nodes();
+            }
+
+            it "should follow heater lifecycle"
+            {
+                save_pin(&repository, "main", 34, PinValue::Digital(false), at(0));
+                save_pin(&repository, "main", 1, PinValue::Analog(0), at(0));
+                assert_eq!(decider.update_phase(&nodes, false, &at(5)), HeaterPhase::PumpOverrun);
+                assert_eq!(decider.update_phase(&nodes, false, &at(11)), HeaterPhase::Idle);
+                assert_eq!(decider.update_phase(&nodes, true, &at(11)), HeaterPhase::OpeningValves);
+                save_pin(&repository, "main", 1, PinValue::Analog(1023), at(11));
+                assert_eq!(decider.update_phase(&nodes, true, &at(12)), HeaterPhase::WarmingActuators);
+                save_pin(&repository, "main", 34, PinValue::Digital(true), at(17));
+                assert_eq!(decider.update_phase(&nodes, true, &at(18)), HeaterPhase::Firing);
+                assert_eq!(decider.update_phase(&nodes, false, &at(18)), HeaterPhase::Firing);
+                save_pin(&repository, "main", 34, PinValue::Digital(false), at(30));
+                assert_eq!(decider.update_phase(&nodes, false, &at(35)), HeaterPhase::PumpOverrun);
+                assert_eq!(decider.update_phase(&nodes, false, &at(41)), HeaterPhase::ZonesClosing);
+                save_pin(&repository, "main", 1, PinValue::Analog(0), at(41));
+                assert_eq!(decider.update_phase(&nodes, false, &at(42)), HeaterPhase::Idle);
+            }
+
+            it "should refuse illegal transitions"
+            {
+                for (from, to) in vec![
+                    (HeaterPhase::Idle, HeaterPhase::Firing),
+                    (HeaterPhase::OpeningValves, HeaterPhase::Firing),
+                    (HeaterPhase::ZonesClosing, HeaterPhase::Firing),
+                    (HeaterPhase::Idle, HeaterPhase::PumpOverrun),
+                    (HeaterPhase::WarmingActuators, HeaterPhase::PumpOverrun),
+                    (HeaterPhase::Firing, HeaterPhase::Idle),
+                    (HeaterPhase::Firing, HeaterPhase::WarmingActuators),
+                ] {
+                    assert!(!from.can_change_to(to), "{:?} -> {:?}", from, to);
+                }
+                assert!(HeaterPhase::WarmingActuators.can_change_to(HeaterPhase::Firing));
+                assert!(HeaterPhase::Firing.can_change_to(HeaterPhase::PumpOverrun));
+
+                assert!(decider.save_phase(HeaterPhase::Idle, &at(0)));
+                assert!(!decider.save_phase(HeaterPhase::Firing, &at(1)));
+                assert_eq!(decider.get_saved_phase(), Some((HeaterPhase::Idle, at(0))));
+            }
+
+            it "should only make legal transitions"
+            {
+                let phases = vec![HeaterPhase::Idle, HeaterPhase::OpeningValves, HeaterPhase::WarmingActuators, HeaterPhase::Firing, HeaterPhase::PumpOverrun, HeaterPhase::ZonesClosing];
+                for phase in &phases {
+                    for flags in 0..16 {
+                        let inputs = HeaterInputs::new(flags & 1 > 0, flags & 2 > 0, flags & 4 > 0, flags & 8 > 0);
+                        let next = phase.next(&inputs);
+                        assert!(phase.can_change_to(next), "{:?} -> {:?} {:?}", phase, next, inputs);
+                    }
+                }
+            }
+
+            it "should not fire from idle when heater is found on"
+            {
+                decider.save_phase(HeaterPhase::Idle, &at(0));
+                save_pin(&repository, "main", 34, PinValue::Digital(true), at(1));
+                assert_eq!(decider.update_phase(&nodes, true, &at(2)), HeaterPhase::OpeningValves);
+                save_pin(&repository, "main", 1, PinValue::Analog(1023), at(3));
+                assert_eq!(decider.update_phase(&nodes, true, &at(4)), HeaterPhase::WarmingActuators);
+                assert_eq!(decider.update_phase(&nodes, true, &at(5)), HeaterPhase::Firing);
+            }
+
+            it "should save transitions only"
+            {
+                assert!(decider.get_saved_phase().is_none());
+                decider.save_phase(HeaterPhase::Idle, &at(0));
+                decider.save_phase(HeaterPhase::Idle, &at(1));
+                assert_eq!(decider.get_saved_phase(), Some((HeaterPhase::Idle, at(0))));
+                decider.save_phase(HeaterPhase::OpeningValves, &at(2));
+                assert_eq!(decider.get_saved_phase(), Some((HeaterPhase::OpeningValves, at(2))));
+                for phase in vec![HeaterPhase::Idle, HeaterPhase::OpeningValves, HeaterPhase::WarmingActuators, HeaterPhase::Firing, HeaterPhase::PumpOverrun, HeaterPhase::ZonesClosing] {
+                    assert_eq!(HeaterPhase::from_name(phase.name()), Some(phase));
+                }
+            }
+        }
+
+        describe "command acknowledgement"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("command_retry_time: 30\ncommand_max_attempts: 3");
+                let repository = PinStateRepository::new(&connection);
+                let decider = CommandDecider::new(&repository, &config);
+                let at = |second: u32| Local.ymd(2019, 8, 1).and_hms(8, 0, 0) + Duration::seconds(second as i64);
+            }
+
+            it "should remove acknowledged commands"
+            {
+                decider.save_sent("main", 1, 1023, &at(0));
+                assert!(decider.is_pending("main", 1, 1023));
+                assert!(!decider.is_pending("main", 1, 0));
+                save_pin(&repository, "main", 1, PinValue::Analog(1023), at(1));
+                assert!(decider.get_commands_to_retry(&at(2)).is_empty());
+                assert!(!decider.is_pending("main", 1, 1023));
+            }
+
+            it "should retry with backoff and raise alarm"
+            {
+                save_pin(&repository, "main", 1, PinValue::Analog(0), at(0));
+                decider.save_sent("main", 1, 1023, &at(0));
+                assert!(decider.get_commands_to_retry(&at(29)).is_empty());
+                for (second, attempts) in vec![(30, 1), (90, 2)] {
+                    let commands = decider.get_commands_to_retry(&at(second));
+                    assert_eq!(commands.iter().map(|c| (c.pin, c.value, c.attempts)).collect::<Vec<_>>(), vec![(1, 1023, attempts)]);
+                    decider.save_sent("main", 1, 1023, &at(second));
+                }
+                assert!(decider.get_commands_to_retry(&at(209)).is_empty());
+                assert!(repository.get_last_event("main", EVENT_COMMAND_FAILED).is_none());
+
+                assert!(decider.get_commands_to_retry(&at(210)).is_empty());
+                let event = repository.get_last_event("main", EVENT_COMMAND_FAILED).expect("alarm");
+                assert_eq!(event.dtc, at(210).naive_local());
+                let command = repository.get_pending_command("main", 1).expect("failed command");
+                assert!(command.failed);
+                assert_eq!(command.last_sent, at(90).naive_local());
+            }
+
+            it "should not resend failed command until value changes"
+            {
+                decider.save_sent("main", 1, 1023, &at(0));
+                for second in vec![30, 90] {
+                    decider.get_commands_to_retry(&at(second));
+                    decider.save_sent("main", 1, 1023, &at(second));
+                }
+                assert!(decider.get_commands_to_retry(&at(210)).is_empty());
+                assert!(decider.get_commands_to_retry(&at(3600)).is_empty());
+                assert!(decider.is_pending("main", 1, 1023));
+                let event = repository.get_last_event("main", EVENT_COMMAND_FAILED).expect("alarm");
+                assert_eq!(event.dtc, at(210).naive_local());
+
+                assert!(!decider.is_pending("main", 1, 0));
+                decider.save_sent("main", 1, 0, &at(3600));
+                assert!(!repository.get_pending_command("main", 1).expect("command").failed);
+
+                save_pin(&repository, "main", 1, PinValue::Analog(0), at(3601));
+                assert!(decider.get_commands_to_retry(&at(3602)).is_empty());
+                assert!(repository.get_pending_command("main", 1).is_none());
+            }
+
+            it "should replace command with new value"
+            {
+                decider.save_sent("main", 1, 1023, &at(0));
+                decider.save_sent("main", 1, 0, &at(10));
+                let command = repository.get_pending_command("main", 1).expect("command");
+                assert_eq!((command.value, command.attempts, command.next_retry), (0, 1, at(40).naive_local()));
+            }
+        }
+
+        describe "valve exercise"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("exercise_enabled: true\nexercise_days: Thu\nexercise_time: 11:00\nexercise_duration: 60");
+                let repository = PinStateRepository::new(&connection);
+                let cycle_decider = CycleDecider::new(&repository, &config);
+                let decider = ExerciseDecider::new(&repository, &cycle_decider, &config);
+                let nodes: ControlNodes = map!{
+                    "main".to_owned() => ControlNode::new("main".to_owned(), 30, map!{
+                        "zone1".to_owned() => create_test_zone(1),
+                        "zone2".to_owned() => create_test_zone(2)
+                    }),
+                    "slave".to_owned() => ControlNode::new("slave".to_owned(), 31, map!{
+                        "zone4".to_owned() => create_test_zone(4)
+                    })
+                };
+                for (node, pin, value) in vec![
+                    ("main", 1, PinValue::Analog(0)),
+                    ("main", 2, PinValue::Analog(0)),
+                    ("main", 30, PinValue::Digital(false)),
+                    ("main", 34, PinValue::Digital(false)),
+                    ("slave", 4, PinValue::Analog(0)),
+                    ("slave", 31, PinValue::Digital(false)),
+                ] {
+                    save_pin(&repository, node, pin, value, Local.ymd(2019, 8, 1).and_hms(10, 0, 0));
+                }
+            }
+
+            it "should open zones in turn"
+            {
+                assert!(decider.get_pins_to_change(&nodes, HeaterPhase::Idle, false, &Local.ymd(2019, 8, 1).and_hms(10, 59, 59)).is_none());
+                for (second, expected) in vec![
+                    (30, map!{ "main".to_owned() => map!{ 1 => PinValue::Analog(1023), 30 => PinValue::Digital(true) } }),
+                    (90, map!{ "main".to_owned() => map!{ 1 => PinValue::Analog(0), 2 => PinValue::Analog(1023) } }),
+                    (150, map!{
+                        "main".to_owned() => map!{ 2 => PinValue::Analog(0), 30 => PinValue::Digital(false) },
+                        "slave".to_owned() => map!{ 4 => PinValue::Analog(1023), 31 => PinValue::Digital(true) }
+                    }),
+                    (210, map!{ "slave".to_owned() => map!{ 4 => PinValue::Analog(0), 31 => PinValue::Digital(false) } }),
+                ] {
+                    let now = Local.ymd(2019, 8, 1).and_hms(11, 0, 0) + Duration::seconds(second);
+                    let changes = decider.get_pins_to_change(&nodes, HeaterPhase::Idle, false, &now).expect("run in progress");
+                    assert_eq!(changes, expected, "second {}", second);
+                    for (node, pins) in changes {
+                        for (pin, value) in pins {
+                            save_pin(&repository, &node, pin, value, now);
+                        }
+                    }
+                }
+                assert_eq!(decider.get_pins_to_change(&nodes, HeaterPhase::Idle, false, &Local.ymd(2019, 8, 1).and_hms(11, 4, 29)), Some(HashMap::new()));
+                assert!(decider.get_pins_to_change(&nodes, HeaterPhase::Idle, false, &Local.ymd(2019, 8, 1).and_hms(11, 4, 30)).is_none());
+                for zone_name in vec!["zone1", "zone2", "zone4"] {
+                    assert!(repository.get_last_event(zone_name, EVENT_EXERCISE).is_some(), "{}", zone_name);
+                }
+            }
+
+            it "should postpone run until the heater is idle"
+            {
+                let at = |minute: u32, second: u32| Local.ymd(2019, 8, 1).and_hms(11, minute, second);
+                assert!(decider.get_pins_to_change(&nodes, HeaterPhase::PumpOverrun, false, &at(0, 30)).is_none());
+                assert!(decider.get_pins_to_change(&nodes, HeaterPhase::Idle, true, &at(1, 0)).is_none());
+                let missed = repository.get_last_event(EXERCISE_EVENT_NAME, EVENT_EXERCISE_MISSED).expect("missed run");
+                assert_eq!(missed.dtc, at(0, 30).naive_local());
+
+                // the missed run is retried after its window
+                let expected = map!{ "main".to_owned() => map!{ 1 => PinValue::Analog(1023), 30 => PinValue::Digital(true) } };
+                assert_eq!(decider.get_pins_to_change(&nodes, HeaterPhase::Idle, false, &at(30, 0)), Some(expected));
+                assert!(decider.get_pins_to_change(&nodes, HeaterPhase::Idle, false, &Local.ymd(2019, 8, 2).and_hms(11, 0, 30)).is_none());
+            }
+
+            it "should stop run on demand and retry it"
+            {
+                let at = |minute: u32, second: u32| Local.ymd(2019, 8, 1).and_hms(11, minute, second);
+                let mut changes = decider.get_pins_to_change(&nodes, HeaterPhase::Idle, false, &at(0, 30)).expect("run started");
+                for (pin, value) in changes.remove("main").expect("main changes") {
+                    save_pin(&repository, "main", pin, value, at(0, 30));
+                }
+                assert!(decider.get_pins_to_change(&nodes, HeaterPhase::ZonesClosing, false, &at(0, 40)).is_some());
+                assert!(decider.get_pins_to_change(&nodes, HeaterPhase::WarmingActuators, true, &at(0, 50)).is_none());
+                assert_eq!(repository.get_last_event(EXERCISE_EVENT_NAME, EVENT_EXERCISE_MISSED).expect("interrupted").dtc, at(0, 50).naive_local());
+                assert!(decider.get_pins_to_change(&nodes, HeaterPhase::ZonesClosing, false, &at(1, 0)).is_none());
+
+                // restarts from the first zone
+                let changes = decider.get_pins_to_change(&nodes, HeaterPhase::Idle, false, &at(20, 0)).expect("run restarted");
+                assert_eq!(changes, HashMap::new());
+                assert_eq!(decider.get_run_start(&at(0, 0)), Some(at(20, 0)));
+            }
+
+            it "should keep pump timing"
+            {
+                let config = create_settings("exercise_enabled: true\nexercise_days: Thu\nexercise_time: 11:00\nzone_min_on_time: 600");
+                let cycle_decider = CycleDecider::new(&repository, &config);
+                let decider = ExerciseDecider::new(&repository, &cycle_decider, &config);
+                let start = Local.ymd(2019, 8, 1).and_hms(11, 0, 30);
+                let mut changes = decider.get_pins_to_change(&nodes, HeaterPhase::Idle, false, &start).expect("run started");
+                for (pin, value) in changes.remove("main").expect("main changes") {
+                    save_pin(&repository, "main", pin, value, start);
+                }
+
+                // main pump has not been on for zone_min_on_time
+                let expected = map!{
+                    "main".to_owned() => map!{ 1 => PinValue::Analog(0) },
+                    "slave".to_owned() => map!{ 4 => PinValue::Analog(1023), 31 => PinValue::Digital(true) }
+                };
+                assert_eq!(decider.get_pins_to_change(&nodes, HeaterPhase::ZonesClosing, false, &(start + Duration::seconds(130))), Some(expected));
+            }
+
+            it "should run only on exercise days"
+            {
+                assert!(decider.get_pins_to_change(&nodes, HeaterPhase::Idle, false, &Local.ymd(2019, 8, 2).and_hms(11, 0, 30)).is_none());
+                let config = create_settings("exercise_days: Thu\nexercise_time: 11:00");
+                let decider = ExerciseDecider::new(&repository, &cycle_decider, &config);
+                assert!(decider.get_pins_to_change(&nodes, HeaterPhase::Idle, false, &Local.ymd(2019, 8, 1).and_hms(11, 0, 30)).is_none());
+            }
+
+            it "should not use heater pin as pump"
+            {
+                let nodes: ControlNodes = map!{
+                    "main".to_owned() => ControlNode::new("main".to_owned(), 34, map!{
+                        "zone1".to_owned() => create_test_zone(1)
+                    })
+                };
+                assert_eq!(
+                    decider.get_pins_to_change(&nodes, HeaterPhase::Idle, false, &Local.ymd(2019, 8, 1).and_hms(11, 0, 30)),
+                    Some(map!{ "main".to_owned() => map!{ 1 => PinValue::Analog(1023) } })
+                );
+            }
+        }
+
+        describe "hot water"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("");
+                let repository = PinStateRepository::new(&connection);
+                let decider = HotWaterDecider::new(&repository, &config);
+                let mut tank = HotWaterZone::new("boiler".to_owned(), 3, 12, Temperature::new(55.0));
+                tank.priority = true;
+                let mut nodes: ControlNodes = map!{
+                    "main".to_owned() => ControlNode::new("main".to_owned(), 30, map!{
+                        "zone1".to_owned() => create_test_zone(1)
+                    })
+                };
+                nodes.get_mut("main").unwrap().hot_water = Some(tank.clone());
+            }
+
+            it "should charge with hysteresis"
+            {
+                assert!(!decider.should_charge("main", &tank, &at(0)));
+                save_temperature(&repository, "boiler", 3, 52.0, at(0));
+                assert!(!decider.should_charge("main", &tank, &at(1)));
+                save_temperature(&repository, "boiler", 3, 49.0, at(20));
+                assert!(decider.should_charge("main", &tank, &at(21)));
+                save_pin(&repository, "main", 12, PinValue::Digital(true), at(21));
+                save_temperature(&repository, "boiler", 3, 53.0, at(40));
+                assert!(decider.should_charge("main", &tank, &at(41)));
+                save_temperature(&repository, "boiler", 3, 55.0, at(55));
+                assert!(!decider.should_charge("main", &tank, &at(56)));
+            }
+
+            it "should average tank temperature over tank_average_time"
+            {
+                save_temperature(&repository, "boiler", 3, 52.0, at(0));
+                save_temperature(&repository, "boiler", 3, 49.0, at(20));
+                assert_eq!(decider.get_tank_temperature(&tank, &at(21)), Some(Temperature::new(49.0)));
+                let config = create_settings("tank_average_time: 1800");
+                let decider = HotWaterDecider::new(&repository, &config);
+                assert_eq!(decider.get_tank_temperature(&tank, &at(21)), Some(Temperature::new(50.5)));
+            }
+
+            it "should provide tank pins to change"
+            {
+                save_pin(&repository, "main", 12, PinValue::Digital(false), at(0));
+                save_temperature(&repository, "boiler", 3, 49.0, at(10));
+                assert!(decider.is_priority_charging(&nodes, &at(11)));
+                assert_eq!(decider.get_pins_to_change(&nodes, &at(11)), map!{ "main".to_owned() => map!{ 12 => PinValue::Digital(true) } });
+                save_pin(&repository, "main", 12, PinValue::Digital(true), at(11));
+                assert_eq!(decider.get_pins_to_change(&nodes, &at(12)), HashMap::new());
+                save_temperature(&repository, "boiler", 3, 56.0, at(30));
+                assert_eq!(decider.get_pins_to_change(&nodes, &at(31)), map!{ "main".to_owned() => map!{ 12 => PinValue::Digital(false) } });
+            }
+
+            it "should keep heater on while charging"
+            {
+                let config = create_settings("outdoor_sensor_name: outside\noutdoor_sensor_pin: 5\noutdoor_cutoff_temperature: 17.0");
+                let heater_decider = HeaterDecider::new(&repository, &config);
+                save_temperature(&repository, "outside", 5, 25.0, at(0));
+                assert!(!heater_decider.should_be_on(&nodes, &at(5)));
+                save_pin(&repository, "main", 12, PinValue::Digital(true), at(5));
+                assert!(heater_decider.should_be_on(&nodes, &at(6)));
+            }
+        }
+
+        describe "heat sources"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("
+outdoor_sensor_name: outside
+outdoor_sensor_pin: 5
+heat_source_stage_delay: 1800
+heat_sources:
+  - control_name: main
+    control_pin: 40
+    output: analog
+    power: 80
+    min_outdoor_temperature: -5.0
+  - control_name: main
+    control_pin: 41
+    stage: 1
+");
+                let repository = PinStateRepository::new(&connection);
+                let decider = HeaterDecider::new(&repository, &config);
+            }
+
+            it "should not switch sources without recorded state"
+            {
+                assert!(decider.get_state().is_none());
+                assert_eq!(decider.get_sources_to_change(true, &at(0)), HashMap::new());
+                save_pin(&repository, "main", 41, PinValue::Digital(false), Local.ymd(2019, 8, 1).and_hms(7, 0, 0));
+                assert_eq!(decider.get_sources_to_change(true, &at(0)), HashMap::new());
+                save_pin(&repository, "main", 40, PinValue::Analog(0), Local.ymd(2019, 8, 1).and_hms(7, 0, 0));
+                assert_eq!(decider.get_sources_to_change(true, &at(0)), map!{ "main".to_owned() => map!{ 40 => PinValue::Analog(percent_to_analog(80)) } });
+            }
+
+            it "should start stages in order"
+            {
+                save_pin(&repository, "main", 40, PinValue::Analog(0), Local.ymd(2019, 8, 1).and_hms(7, 0, 0));
+                save_pin(&repository, "main", 41, PinValue::Digital(false), Local.ymd(2019, 8, 1).and_hms(7, 0, 0));
+                assert_eq!(decider.get_state().map(|state| state.is_on()), Some(false));
+                assert_eq!(decider.get_sources_to_change(true, &at(0)), map!{ "main".to_owned() => map!{ 40 => PinValue::Analog(percent_to_analog(80)) } });
+                save_pin(&repository, "main", 40, PinValue::Analog(percent_to_analog(80)), at(0));
+                assert_eq!(decider.get_state().map(|state| (state.is_on(), state.dt)), Some((true, at(0))));
+                assert_eq!(decider.get_sources_to_change(true, &at(20)), HashMap::new());
+                assert_eq!(decider.get_sources_to_change(true, &at(30)), map!{ "main".to_owned() => map!{ 41 => PinValue::Digital(true) } });
+                save_pin(&repository, "main", 41, PinValue::Digital(true), at(30));
+                assert_eq!(
+                    decider.get_sources_to_change(false, &at(40)),
+                    map!{ "main".to_owned() => map!{ 40 => PinValue::Analog(0), 41 => PinValue::Digital(false) } }
+                );
+            }
+
+            it "should skip disabled sources"
+            {
+                save_pin(&repository, "main", 41, PinValue::Digital(false), Local.ymd(2019, 8, 1).and_hms(7, 0, 0));
+                save_temperature(&repository, "outside", 5, -10.0, at(0));
+                assert_eq!(decider.get_sources_to_change(true, &at(1)), map!{ "main".to_owned() => map!{ 41 => PinValue::Digital(true) } });
+                save_pin(&repository, "main", 40, PinValue::Analog(percent_to_analog(80)), at(1));
+                assert_eq!(
+                    decider.get_sources_to_change(true, &at(2)),
+                    map!{ "main".to_owned() => map!{ 40 => PinValue::Analog(0), 41 => PinValue::Digital(true) } }
+                );
+            }
+
+            it "should report heater off since the last source"
+            {
+                save_pin(&repository, "main", 40, PinValue::Analog(0), at(10));
+                save_pin(&repository, "main", 41, PinValue::Digital(false), at(20));
+                assert_eq!(decider.get_state().map(|state| (state.is_on(), state.dt)), Some((false, at(20))));
             }
         }
 
@@ -185,8 +1980,7 @@ mod test_deciders
         {
             before
             {
-                let connection = SqliteConnection::establish(":memory:").unwrap();
-                embedded_migrations::run(&connection);
+                let (connection, _) = create_fixture("");
                 let config = Settings::new(Config::new("test".to_owned(), "host".to_owned(), "main".to_owned(), 34));
                 let repository = create_repository(&connection);
                 let heater_decider = HeaterDecider::new(&repository, &config);
@@ -1,9 +1,130 @@
-use crate::config::ControlNodes;
+use crate::config::{ControlNodes, Settings};
 use crate::repository::{PinStateRepository};
+use crate::zone::rom_sensor_name;
+use arduino_mqtt_pin::pin::Temperature;
 use mosquitto_client::Mosquitto;
-use log::{debug, warn};
-use chrono::{Local, Duration};
+use std::io::{Error, ErrorKind};
+use log::{debug, info, warn};
+use chrono::{DateTime, Local};
 use json::object;
+use derive_new::{new};
+
+// temporary temperature for a zone, duration in minutes. zero duration removes the override
+#[derive(Debug, new, PartialEq)]
+pub struct OverrideCommand
+{
+    pub zone_name: String,
+    pub temperature: f32,
+    pub duration: u32
+}
+
+// prefix/master/override/zone-name {"temperature": 23.0, "duration": 120}
+pub fn override_from_message(namespace: &str, topic: &str, payload: &str) -> Option<OverrideCommand>
+{
+    let prefix = format!("{namespace}/master/override/", namespace=namespace);
+    if !topic.starts_with(&prefix) {
+        return None;
+    }
+    let zone_name = &topic[prefix.len()..];
+    if zone_name.is_empty() || zone_name.contains('/') {
+        return None;
+    }
+    let data = json::parse(payload).ok()?;
+    let duration = data["duration"].as_u32()?;
+    let temperature = if duration > 0 { data["temperature"].as_f32()? } else { 0.0 };
+    Some(OverrideCommand::new(zone_name.to_owned(), temperature, duration))
+}
+
+// prefix/nodes/node-id/current/temperature/rom/28ff4a1c6416035b 21.5
+// saved as "node-id:28ff4a1c6416035b" pin 0 for zones with sensor_address
+pub fn rom_temperature_from_message(namespace: &str, topic: &str, payload: &str) -> Option<(String, Temperature)>
+{
+    let prefix = format!("{namespace}/nodes/", namespace=namespace);
+    if !topic.starts_with(&prefix) {
+        return None;
+    }
+    let parts: Vec<&str> = topic[prefix.len()..].split('/').collect();
+    match parts.as_slice() {
+        [node, "current", "temperature", "rom", address] if !node.is_empty() && !address.is_empty() => {
+            let temperature = payload.trim().parse::<f32>().ok()?;
+            Some((rom_sensor_name(node, address), Temperature::new(temperature)))
+        },
+        _ => None
+    }
+}
+
+// received message that may change expected pin states
+#[derive(Debug, PartialEq)]
+pub enum Trigger
+{
+    Reading(String, u8),
+    Override(OverrideCommand)
+}
+
+// readings of pins and sensors not used by config do not need a new evaluation
+pub fn is_relevant_trigger(trigger: &Trigger, config: &Settings, control_nodes: &ControlNodes) -> bool
+{
+    let (node, pin) = match trigger {
+        Trigger::Override(_) => return true,
+        Trigger::Reading(node, pin) => (node.as_str(), *pin)
+    };
+    if config.outdoor_sensor_name().map_or(false, |name| name == node && config.outdoor_sensor_pin() == pin) {
+        return true;
+    }
+    if config.heat_sources().iter().any(|source| source.control_name == node && source.control_pin == pin) {
+        return true;
+    }
+    control_nodes.iter().any(|(control_name, control_node)| {
+        (control_name == node && (control_node.control_pin == pin || control_node.zones.values().any(|zone| zone.control_pin == pin)))
+            || control_node.zones.values().any(|zone| zone.get_sensor_name() == node && zone.get_sensor_pin() == pin)
+            || control_node.hot_water.as_ref().map_or(false, |tank|
+                (control_name == node && tank.control_pin == pin) || (tank.sensor_name == node && tank.sensor_pin == pin)
+            )
+    })
+}
+
+// overrides for zones missing in config are ignored, as in the ui
+pub fn save_override_command(repository: &PinStateRepository, control_nodes: &ControlNodes, command: &OverrideCommand, now: &DateTime<Local>) -> bool
+{
+    if !control_nodes.values().any(|control| control.zones.contains_key(&command.zone_name)) {
+        warn!("Override ignored for unknown zone: {}", command.zone_name);
+        return false;
+    }
+    let result = if command.duration > 0 {
+        info!("Override zone: {} temperature: {} for {} minutes", command.zone_name, command.temperature, command.duration);
+        repository.save_override(&command.zone_name, command.temperature, &(*now + chrono::Duration::minutes(command.duration as i64)), now)
+    } else {
+        info!("Override removed for zone: {}", command.zone_name);
+        repository.remove_override(&command.zone_name)
+    };
+    if let Err(e) = result {
+        warn!("Failed to save override for {} {:?}", command.zone_name, e);
+    }
+    true
+}
+
+// broker port, credentials and tls come from config, client id gets the binary suffix
+pub fn connect_client(config: &Settings, suffix: &str) -> Result<Mosquitto, Error>
+{
+    let mqtt = config.mqtt();
+    let client_id = format!("{}-{}", mqtt.client_id.clone().unwrap_or_else(|| config.name()), suffix);
+    let client = Mosquitto::new(&client_id);
+    if let Some(username) = &mqtt.username {
+        let password = mqtt.get_password()?;
+        client.set_username_and_password(Some(username.as_str()), password.as_ref().map(|p| p.as_str()))
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Unable to set mqtt username {} {}", username, e)))?;
+    }
+    if let Some(ca_file) = &mqtt.ca_file {
+        let cert_file = mqtt.cert_file.as_ref().map_or("", |f| f.as_str());
+        let key_file = mqtt.key_file.as_ref().map_or("", |f| f.as_str());
+        client.tls_set(ca_file.as_str(), cert_file, key_file, None)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Unable to set mqtt tls {} {}", ca_file, e)))?;
+    }
+    client.connect(&config.host(), mqtt.port.into())
+        .map_err(|e| Error::new(ErrorKind::NotConnected, format!("Unable to connect to host: {}:{} {}", config.host(), mqtt.port, e)))?;
+    info!("Connected to {}:{} as {}", config.host(), mqtt.port, client_id);
+    Ok(client)
+}
 
 pub fn send_to_zone(client: &Mosquitto, pin: u8, value: u16, namespace: &str, name: &str) -> bool
 {
@@ -30,12 +151,43 @@ pub fn send_to_zone(client: &Mosquitto, pin: u8, value: u16, namespace: &str, na
     true
 }
 
-pub fn print_info(repository: &PinStateRepository, control_nodes: &ControlNodes)
+// topic and payload read by override_from_message
+pub fn override_to_message(namespace: &str, command: &OverrideCommand) -> (String, String)
+{
+    let data = object!{
+        "temperature" => command.temperature,
+        "duration" => command.duration
+    };
+    (format!("{namespace}/master/override/{zone}", namespace=namespace, zone=command.zone_name), data.dump())
+}
+
+// published like a remote override so the controller saves it and evaluates right away
+pub fn send_override(client: &Mosquitto, namespace: &str, command: &OverrideCommand) -> bool
+{
+    let (topic, payload) = override_to_message(namespace, command);
+
+    let result = client.publish(
+        &topic,
+        payload.as_bytes(),
+        1,
+        false
+    );
+
+    debug!("Message sent: {} {}", topic, payload);
+
+    if let Err(e) = result {
+        warn!("Unable to send override for {} {}", command.zone_name, e);
+        return false;
+    }
+    true
+}
+
+pub fn print_info(repository: &PinStateRepository, config: &Settings, control_nodes: &ControlNodes, now: &DateTime<Local>)
 {
     for (control_name, node) in control_nodes {
         for (zone_name, zone) in &node.zones {
             let state = repository.get_last_changed_pin_state(control_name, zone.control_pin);
-            let temp = repository.get_average_temperature(zone_name, zone.sensor_pin, &(Local::now() - Duration::hours(1)));
+            let temp = repository.get_average_temperature(&zone.get_sensor_name(), zone.get_sensor_pin(), &(*now - zone.get_temperature_average_time(config)));
             debug!("Node: {} Zone: {} State: {:?} Temperature: {:?}", control_name, zone_name, state, temp);
         }
         if node.control_pin > 0 {
@@ -58,3 +210,122 @@ macro_rules! map(
      };
 );
 
+
+
+#[cfg(test)]
+mod tests
+{
+    use speculate::speculate;
+    use super::*;
+    use crate::repository::test_repository::create_nodes;
+    use crate::config::tests::{create_settings, create_fixture};
+    use chrono::TimeZone;
+
+    speculate! {
+        describe "override messages"
+        {
+            it "should parse override command"
+            {
+                assert_eq!(
+                    override_from_message("sildymas", "sildymas/master/override/vonia", "{\"temperature\": 23.0, \"duration\": 120}"),
+                    Some(OverrideCommand::new("vonia".to_owned(), 23.0, 120))
+                );
+                assert_eq!(
+                    override_from_message("sildymas", "sildymas/master/override/vonia", "{\"duration\": 0}"),
+                    Some(OverrideCommand::new("vonia".to_owned(), 0.0, 0))
+                );
+            }
+
+            it "should send override as the controller reads it"
+            {
+                let command = OverrideCommand::new("vonia".to_owned(), 22.5, 90);
+                let (topic, payload) = override_to_message("sildymas", &command);
+                assert_eq!(topic, "sildymas/master/override/vonia");
+                assert_eq!(override_from_message("sildymas", &topic, &payload), Some(command));
+            }
+
+            it "should parse rom temperature"
+            {
+                assert_eq!(
+                    rom_temperature_from_message("sildymas", "sildymas/nodes/bedroom/current/temperature/rom/28-FF-4A-1C-64-16-03-5B", "21.5"),
+                    Some(("bedroom:28ff4a1c6416035b".to_owned(), Temperature::new(21.5)))
+                );
+                for (topic, payload) in vec![
+                    ("sildymas/nodes/bedroom/current/temperature/2", "21.5"),
+                    ("sildymas/nodes/bedroom/current/temperature/rom/", "21.5"),
+                    ("sildymas/nodes/bedroom/current/temperature/rom/28ff4a1c6416035b", "error"),
+                    ("other/nodes/bedroom/current/temperature/rom/28ff4a1c6416035b", "21.5"),
+                ] {
+                    assert!(rom_temperature_from_message("sildymas", topic, payload).is_none(), "{} {}", topic, payload);
+                }
+            }
+
+            it "should ignore other messages"
+            {
+                for (topic, payload) in vec![
+                    ("sildymas/master/analog/timeout/3", "1"),
+                    ("other/master/override/vonia", "{\"temperature\": 23.0, \"duration\": 120}"),
+                    ("sildymas/master/override/", "{\"temperature\": 23.0, \"duration\": 120}"),
+                    ("sildymas/master/override/vonia/set", "{\"temperature\": 23.0, \"duration\": 120}"),
+                    ("sildymas/master/override/vonia", "{\"temperature\": 23.0}"),
+                    ("sildymas/master/override/vonia", "{\"duration\": 10}"),
+                    ("sildymas/master/override/vonia", "23"),
+                ] {
+                    assert!(override_from_message("sildymas", topic, payload).is_none(), "{} {}", topic, payload);
+                }
+            }
+        }
+
+        describe "triggers"
+        {
+            it "should react to configured pins and sensors only"
+            {
+                let config = create_settings("heater_control_name: boiler\nheater_control_pin: 30\noutdoor_sensor_name: outside\noutdoor_sensor_pin: 2");
+                let nodes = create_nodes();
+                for trigger in vec![
+                    Trigger::Override(OverrideCommand::new("zone1".to_owned(), 23.0, 120)),
+                    Trigger::Reading("zone1".to_owned(), 4),
+                    Trigger::Reading("main".to_owned(), 2),
+                    Trigger::Reading("main".to_owned(), 34),
+                    Trigger::Reading("boiler".to_owned(), 30),
+                    Trigger::Reading("outside".to_owned(), 2),
+                ] {
+                    assert!(is_relevant_trigger(&trigger, &config, &nodes), "{:?}", trigger);
+                }
+                for trigger in vec![
+                    Trigger::Reading("zone1".to_owned(), 3),
+                    Trigger::Reading("zone3".to_owned(), 4),
+                    Trigger::Reading("main".to_owned(), 3),
+                    Trigger::Reading("outside".to_owned(), 3),
+                ] {
+                    assert!(!is_relevant_trigger(&trigger, &config, &nodes), "{:?}", trigger);
+                }
+            }
+        }
+
+        describe "override commands"
+        {
+            before
+            {
+                let (connection, _) = create_fixture("");
+                let repository = PinStateRepository::new(&connection);
+                let nodes = create_nodes();
+                let now = Local.ymd(2019, 8, 2).and_hms(8, 0, 0);
+            }
+
+            it "should save override for configured zone"
+            {
+                assert!(save_override_command(&repository, &nodes, &OverrideCommand::new("zone1".to_owned(), 23.0, 120), &now));
+                assert_eq!(repository.get_active_override("zone1", &now).expect("override").temperature, 23.0);
+                assert!(save_override_command(&repository, &nodes, &OverrideCommand::new("zone1".to_owned(), 0.0, 0), &now));
+                assert!(repository.get_active_override("zone1", &now).is_none());
+            }
+
+            it "should ignore override for unknown zone"
+            {
+                assert!(!save_override_command(&repository, &nodes, &OverrideCommand::new("zone3".to_owned(), 23.0, 120), &now));
+                assert!(repository.get_active_override("zone3", &now).is_none());
+            }
+        }
+    }
+}
@@ -1,18 +1,22 @@
 use arduino_mqtt_pin::pin::{PinOperation, Temperature, PinCollection, PinState, PinValue};
 use std::collections::HashMap;
 use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
-use diesel::{insert_into, RunQueryDsl, SqliteConnection};
+use diesel::{insert_into, delete, RunQueryDsl, SqliteConnection};
 use diesel::prelude::*;
-use arduino_mqtt_pin::helper::average;
 
-use crate::config::ControlNodes;
+use crate::config::{ControlNodes, Settings, TemperatureFilter};
 use crate::schema::pin_states;
 use crate::schema::temperatures;
+use crate::schema::zone_overrides;
+use crate::schema::events;
+use crate::schema::pending_commands;
+use crate::schema::pid_states;
 use crate::schema::pin_states::BoxedQuery;
 use diesel::query_dsl::QueryDsl;
 use uuid::Uuid;
 use diesel::sqlite::{Sqlite};
 use derive_new::{new};
+use std::convert::TryFrom;
 
 pub type States = HashMap<String, HashMap<u8, PinCollection>>;
 
@@ -39,10 +43,94 @@ struct PinTemperature
     dtc: NaiveDateTime
 }
 
+// temporary expected temperature for a zone until expires
+#[derive(new, Insertable, Queryable, Identifiable, Debug, PartialEq, Clone)]
+#[table_name = "zone_overrides"]
+pub struct ZoneOverride
+{
+    pub id: String,
+    pub name: String,
+    pub temperature: f32,
+    pub expires: NaiveDateTime,
+    pub dtc: NaiveDateTime
+}
+
+pub const EVENT_WINDOW_OPEN: &str = "window_open";
+pub const EVENT_SWITCH_BLOCKED: &str = "switch_blocked";
+pub const EVENT_EXERCISE: &str = "exercise";
+pub const EVENT_EXERCISE_RUN: &str = "exercise_run";
+pub const EVENT_EXERCISE_MISSED: &str = "exercise_missed";
+pub const EVENT_SENSOR_STALE: &str = "sensor_stale";
+pub const EVENT_SENSOR_UNREPORTED: &str = "sensor_unreported";
+pub const EVENT_COMMAND_FAILED: &str = "command_failed";
+pub const EVENT_HEATER_PHASE: &str = "heater_phase";
+
+// something the controller did or noticed, e.g. a zone suspended for an open window
+#[derive(new, Insertable, Queryable, Identifiable, Debug, PartialEq, Clone)]
+#[table_name = "events"]
+pub struct Event
+{
+    pub id: String,
+    pub name: String,
+    pub kind: String,
+    pub message: String,
+    pub dtc: NaiveDateTime
+}
+
+// pin value sent to a control node and not yet reported back by it
+// failed commands are kept until acknowledged or replaced by another value
+#[derive(new, Insertable, Queryable, Identifiable, Debug, PartialEq, Clone)]
+#[table_name = "pending_commands"]
+pub struct PendingCommand
+{
+    pub id: String,
+    pub name: String,
+    pub pin: i32,
+    pub value: i32,
+    pub attempts: i32,
+    pub next_retry: NaiveDateTime,
+    pub dtc: NaiveDateTime,
+    pub last_sent: NaiveDateTime,
+    #[new(default)]
+    pub failed: bool
+}
+
+impl PendingCommand
+{
+    // none for rows that do not fit a pin and its value
+    pub fn get_pin_value(&self) -> Option<(u8, u16)>
+    {
+        Some((u8::try_from(self.pin).ok()?, u16::try_from(self.value).ok()?))
+    }
+}
+
+// pid controller state of a zone, kept while the zone stays on
+#[derive(new, Insertable, Queryable, Identifiable, Debug, PartialEq, Clone)]
+#[table_name = "pid_states"]
+pub struct PidState
+{
+    pub id: String,
+    pub name: String,
+    pub integral: f32,
+    pub last_error: f32,
+    pub last_update: NaiveDateTime
+}
+
 #[derive(new)]
 pub struct PinStateRepository<'a>
 {
-    conn: &'a SqliteConnection
+    conn: &'a SqliteConnection,
+    #[new(default)]
+    config: Option<&'a Settings>
+}
+
+impl<'a> PinStateRepository<'a>
+{
+    // temperature readings are filtered by config, defaults are used without it
+    pub fn with_config(self, config: &'a Settings) -> PinStateRepository<'a>
+    {
+        PinStateRepository { config: Some(config), ..self }
+    }
 }
 
 struct PinStateBuilder
@@ -198,15 +286,175 @@ impl PinStateRepository<'_>
         None
     }
 
+    fn temperature_filter(&self) -> TemperatureFilter
+    {
+        self.config.map_or_else(TemperatureFilter::default, |config| config.temperature_filter())
+    }
+
+    // impossible values and spikes are left out
     pub fn get_average_temperature(&self, name_id: &str, pin_id: u8, since: &DateTime<Local>) -> Option<Temperature>
+    {
+        let filter = self.temperature_filter();
+        let values: Vec<f32> = filter.filter(&self.load_temperatures(name_id, pin_id, since)).into_iter()
+            .map(|(_, t)| t)
+            .collect();
+        filter.aggregate(&values).map(Temperature::new)
+    }
+
+    // readings with impossible values are not seen
+    pub fn get_last_temperature_dt(&self, name_id: &str, pin_id: u8) -> Option<DateTime<Local>>
+    {
+        use crate::schema::temperatures::dsl::*;
+        let filter = self.temperature_filter();
+        temperatures.filter(pin.eq(pin_id as i32))
+            .filter(name.eq(name_id))
+            .filter(temperature.between(filter.min, filter.max))
+            .order(dtc.desc())
+            .select(dtc)
+            .first::<NaiveDateTime>(self.conn)
+            .ok()
+            .and_then(|dt| Local.from_local_datetime(&dt).single())
+    }
+
+    // readings ordered by time, impossible values and spikes are left out
+    pub fn get_temperatures(&self, name_id: &str, pin_id: u8, since: &DateTime<Local>) -> Vec<(DateTime<Local>, Temperature)>
+    {
+        self.temperature_filter().filter(&self.load_temperatures(name_id, pin_id, since)).into_iter()
+            .filter_map(|(dt, t)| Local.from_local_datetime(&dt).single().map(|dt| (dt, Temperature::new(t))))
+            .collect()
+    }
+
+    fn load_temperatures(&self, name_id: &str, pin_id: u8, since: &DateTime<Local>) -> Vec<(NaiveDateTime, f32)>
     {
         use crate::schema::temperatures::dsl::*;
-        let values = temperatures.filter(pin.eq(pin_id as i32))
+        temperatures.filter(pin.eq(pin_id as i32))
             .filter(dtc.ge(since.naive_local()))
             .filter(name.eq(name_id))
-            .select(temperature)
-            .load::<f32>(self.conn);
-        values.ok().and_then(|v| if v.len() > 0 { Some(v) } else { None }).map(|v| Temperature::new(average(&v)))
+            .order(dtc.asc())
+            .select((dtc, temperature))
+            .load::<(NaiveDateTime, f32)>(self.conn)
+            .unwrap_or(vec![])
+    }
+
+    pub fn save_event(&self, event_name: &str, event_kind: &str, event_message: &str, now: &DateTime<Local>) -> QueryResult<usize>
+    {
+        use crate::schema::events::dsl::{events};
+        let event = Event::new(format!("{}", Uuid::new_v4()), event_name.to_owned(), event_kind.to_owned(), event_message.to_owned(), now.naive_local());
+        insert_into(events).values(&event).execute(self.conn)
+    }
+
+    pub fn get_last_event(&self, event_name: &str, event_kind: &str) -> Option<Event>
+    {
+        use crate::schema::events::dsl::{events, name, kind, dtc};
+        events.filter(name.eq(event_name))
+            .filter(kind.eq(event_kind))
+            .order(dtc.desc())
+            .first::<Event>(self.conn)
+            .ok()
+    }
+
+    // replaces any previous command for the pin
+    pub fn save_command(&self, control_name: &str, pin_id: u8, command_value: u16, next_retry: &DateTime<Local>, now: &DateTime<Local>) -> QueryResult<usize>
+    {
+        use crate::schema::pending_commands::dsl::{pending_commands};
+        self.remove_command(control_name, pin_id)?;
+        let command = PendingCommand::new(format!("{}", Uuid::new_v4()), control_name.to_owned(), pin_id as i32, command_value as i32, 1, next_retry.naive_local(), now.naive_local(), now.naive_local());
+        insert_into(pending_commands).values(&command).execute(self.conn)
+    }
+
+    pub fn update_command_retry(&self, command_id: &str, command_attempts: i32, retry: &DateTime<Local>, now: &DateTime<Local>) -> QueryResult<usize>
+    {
+        use crate::schema::pending_commands::dsl::{pending_commands, id, attempts, next_retry, last_sent};
+        diesel::update(pending_commands.filter(id.eq(command_id)))
+            .set((attempts.eq(command_attempts), next_retry.eq(retry.naive_local()), last_sent.eq(now.naive_local())))
+            .execute(self.conn)
+    }
+
+    pub fn set_command_failed(&self, command_id: &str) -> QueryResult<usize>
+    {
+        use crate::schema::pending_commands::dsl::{pending_commands, id, failed};
+        diesel::update(pending_commands.filter(id.eq(command_id)))
+            .set(failed.eq(true))
+            .execute(self.conn)
+    }
+
+    pub fn remove_command_by_id(&self, command_id: &str) -> QueryResult<usize>
+    {
+        use crate::schema::pending_commands::dsl::{pending_commands, id};
+        delete(pending_commands.filter(id.eq(command_id))).execute(self.conn)
+    }
+
+    pub fn remove_command(&self, control_name: &str, pin_id: u8) -> QueryResult<usize>
+    {
+        use crate::schema::pending_commands::dsl::{pending_commands, name, pin};
+        delete(pending_commands.filter(name.eq(control_name)).filter(pin.eq(pin_id as i32))).execute(self.conn)
+    }
+
+    pub fn get_pending_command(&self, control_name: &str, pin_id: u8) -> Option<PendingCommand>
+    {
+        use crate::schema::pending_commands::dsl::{pending_commands, name, pin};
+        pending_commands.filter(name.eq(control_name))
+            .filter(pin.eq(pin_id as i32))
+            .first::<PendingCommand>(self.conn)
+            .ok()
+    }
+
+    pub fn get_pending_commands(&self) -> Vec<PendingCommand>
+    {
+        use crate::schema::pending_commands::dsl::{pending_commands, dtc};
+        pending_commands.order(dtc.asc())
+            .load::<PendingCommand>(self.conn)
+            .unwrap_or(vec![])
+    }
+
+    // replaces any previous override for the zone
+    pub fn save_override(&self, zone_name: &str, temperature: f32, expires: &DateTime<Local>, now: &DateTime<Local>) -> QueryResult<usize>
+    {
+        use crate::schema::zone_overrides::dsl::{zone_overrides};
+        self.remove_override(zone_name)?;
+        let zone_override = ZoneOverride::new(format!("{}", Uuid::new_v4()), zone_name.to_owned(), temperature, expires.naive_local(), now.naive_local());
+        insert_into(zone_overrides).values(&zone_override).execute(self.conn)
+    }
+
+    pub fn remove_override(&self, zone_name: &str) -> QueryResult<usize>
+    {
+        use crate::schema::zone_overrides::dsl::{zone_overrides, name};
+        delete(zone_overrides.filter(name.eq(zone_name))).execute(self.conn)
+    }
+
+    // replaces any previous pid state for the zone
+    pub fn save_pid_state(&self, zone_name: &str, integral: f32, last_error: f32, now: &DateTime<Local>) -> QueryResult<usize>
+    {
+        use crate::schema::pid_states::dsl::{pid_states};
+        self.remove_pid_state(zone_name)?;
+        let state = PidState::new(format!("{}", Uuid::new_v4()), zone_name.to_owned(), integral, last_error, now.naive_local());
+        insert_into(pid_states).values(&state).execute(self.conn)
+    }
+
+    pub fn remove_pid_state(&self, zone_name: &str) -> QueryResult<usize>
+    {
+        use crate::schema::pid_states::dsl::{pid_states, name};
+        delete(pid_states.filter(name.eq(zone_name))).execute(self.conn)
+    }
+
+    pub fn get_pid_state(&self, zone_name: &str) -> Option<PidState>
+    {
+        use crate::schema::pid_states::dsl::{pid_states, name, last_update};
+        pid_states.filter(name.eq(zone_name))
+            .order(last_update.desc())
+            .first::<PidState>(self.conn)
+            .ok()
+    }
+
+    pub fn get_active_override(&self, zone_name: &str, now: &DateTime<Local>) -> Option<ZoneOverride>
+    {
+        use crate::schema::zone_overrides::dsl::{zone_overrides, name, expires, dtc};
+        zone_overrides.filter(name.eq(zone_name))
+            .filter(expires.gt(now.naive_local()))
+            .filter(dtc.le(now.naive_local()))
+            .order(dtc.desc())
+            .first::<ZoneOverride>(self.conn)
+            .ok()
     }
 
     // node1 1 333 12:32:32
@@ -266,6 +514,7 @@ pub mod test_repository
     use chrono::{TimeZone, NaiveTime};
     use crate::zone::{Zone, Interval};
     use crate::config::{ControlNode};
+    use crate::config::tests::create_settings;
     use crate::embedded_migrations;
 
     pub fn get_data() -> HashMap<String, HashMap<u8, Vec<PinState>>>
@@ -376,6 +625,22 @@ pub mod test_repository
         Zone::new(format!("zone{}", control_pin), 4, intervals, control_pin)
     }
 
+    // decider scenarios start at 8:00, minutes may go past the hour
+    pub fn at(minute: u32) -> DateTime<Local>
+    {
+        Local.ymd(2019, 8, 1).and_hms(8, 0, 0) + chrono::Duration::minutes(minute as i64)
+    }
+
+    pub fn save_pin(repository: &PinStateRepository, node: &str, pin: u8, value: PinValue, dt: DateTime<Local>)
+    {
+        repository.save_state(&PinOperation::new(PinState::new(pin, value, dt, None), node.to_owned()));
+    }
+
+    pub fn save_temperature(repository: &PinStateRepository, sensor: &str, pin: u8, temperature: f32, dt: DateTime<Local>)
+    {
+        save_pin(repository, sensor, pin, PinValue::Temperature(Temperature::new(temperature)), dt);
+    }
+
     pub fn create_nodes() -> ControlNodes
     {
         let nodes = map!{"main".to_owned() => ControlNode::new(
@@ -462,6 +727,71 @@ pub mod test_repository
 
             }
 
+            it "should get active override"
+            {
+                let now = Local.ymd(2019, 8, 2).and_hms(8, 0, 0);
+                assert!(repository.get_active_override("zone1", &now).is_none());
+
+                repository.save_override("zone1", 23.0, &Local.ymd(2019, 8, 2).and_hms(10, 0, 0), &now).unwrap();
+                assert_eq!(repository.get_active_override("zone1", &now).expect("override").temperature, 23.0);
+                assert!(repository.get_active_override("zone2", &now).is_none());
+                assert!(repository.get_active_override("zone1", &Local.ymd(2019, 8, 2).and_hms(9, 59, 59)).is_some());
+                assert!(repository.get_active_override("zone1", &Local.ymd(2019, 8, 2).and_hms(10, 0, 0)).is_none());
+
+                repository.save_override("zone1", 18.0, &Local.ymd(2019, 8, 2).and_hms(12, 0, 0), &now).unwrap();
+                let zone_override = repository.get_active_override("zone1", &Local.ymd(2019, 8, 2).and_hms(11, 0, 0)).expect("replaced override");
+                assert_eq!(zone_override.temperature, 18.0);
+                assert_eq!(zone_override.expires, Local.ymd(2019, 8, 2).and_hms(12, 0, 0).naive_local());
+
+                repository.remove_override("zone1").unwrap();
+                assert!(repository.get_active_override("zone1", &Local.ymd(2019, 8, 2).and_hms(11, 0, 0)).is_none());
+            }
+
+            it "should replace pid state"
+            {
+                assert!(repository.get_pid_state("zone1").is_none());
+                repository.save_pid_state("zone1", 2.0, 0.5, &Local.ymd(2019, 8, 2).and_hms(8, 0, 0)).unwrap();
+                repository.save_pid_state("zone1", 4.5, 0.3, &Local.ymd(2019, 8, 2).and_hms(8, 10, 0)).unwrap();
+                let state = repository.get_pid_state("zone1").expect("pid state");
+                assert_eq!((state.integral, state.last_error), (4.5, 0.3));
+                assert_eq!(state.last_update, Local.ymd(2019, 8, 2).and_hms(8, 10, 0).naive_local());
+                assert!(repository.get_pid_state("zone2").is_none());
+
+                repository.remove_pid_state("zone1").unwrap();
+                assert!(repository.get_pid_state("zone1").is_none());
+            }
+
+            it "should get temperatures in order"
+            {
+                let temperatures = repository.get_temperatures("zone1", 4, &Local.ymd(2019, 8, 2).and_hms(8, 0, 0));
+                assert_eq!(temperatures, vec![
+                    (Local.ymd(2019, 8, 2).and_hms(8, 3, 0), Temperature::new(17.0)),
+                    (Local.ymd(2019, 8, 2).and_hms(8, 30, 0), Temperature::new(18.5)),
+                    (Local.ymd(2019, 8, 2).and_hms(8, 50, 0), Temperature::new(19.5)),
+                    (Local.ymd(2019, 8, 2).and_hms(8, 55, 0), Temperature::new(19.5)),
+                ]);
+                assert!(repository.get_temperatures("zone1", 5, &Local.ymd(2019, 8, 2).and_hms(8, 0, 0)).is_empty());
+            }
+
+            it "should get last temperature dt"
+            {
+                assert_eq!(repository.get_last_temperature_dt("zone1", 4), Some(Local.ymd(2019, 8, 2).and_hms(8, 55, 0)));
+                assert!(repository.get_last_temperature_dt("zone1", 5).is_none());
+                assert!(repository.get_last_temperature_dt("zone3", 4).is_none());
+            }
+
+            it "should get last event"
+            {
+                assert!(repository.get_last_event("zone1", EVENT_WINDOW_OPEN).is_none());
+                repository.save_event("zone1", EVENT_WINDOW_OPEN, "first", &Local.ymd(2019, 8, 2).and_hms(8, 0, 0)).unwrap();
+                repository.save_event("zone1", EVENT_WINDOW_OPEN, "second", &Local.ymd(2019, 8, 2).and_hms(9, 0, 0)).unwrap();
+                repository.save_event("zone2", EVENT_WINDOW_OPEN, "other zone", &Local.ymd(2019, 8, 2).and_hms(10, 0, 0)).unwrap();
+                let event = repository.get_last_event("zone1", EVENT_WINDOW_OPEN).expect("event");
+                assert_eq!(event.message, "second");
+                assert_eq!(event.dtc, Local.ymd(2019, 8, 2).and_hms(9, 0, 0).naive_local());
+                assert!(repository.get_last_event("zone1", "other").is_none());
+            }
+
             it "should get last dt on"
             {
                 let nodes = create_nodes();
@@ -479,6 +809,102 @@ pub mod test_repository
                     None
                 );
             }
+
+            it "should reject impossible temperatures and spikes"
+            {
+                let since = Local.ymd(2019, 8, 3).and_hms(8, 0, 0);
+                for (temperature, minute) in vec![(20.0, 1), (-127.0, 2), (20.5, 3), (85.0, 4), (21.0, 5), (21.5, 6)] {
+                    repository.save_state(&PinOperation::new(
+                        PinState::new(4, PinValue::Temperature(Temperature::new(temperature)), Local.ymd(2019, 8, 3).and_hms(8, minute, 0), None),
+                        "zone3".to_owned()
+                    ));
+                }
+                assert_eq!(repository.get_average_temperature("zone3", 4, &since), Some(Temperature::new(20.75)));
+                assert_eq!(
+                    repository.get_temperatures("zone3", 4, &since).iter().map(|(_, t)| t.value).collect::<Vec<f32>>(),
+                    vec![20.0, 20.5, 21.0, 21.5]
+                );
+
+                repository.save_state(&PinOperation::new(
+                    PinState::new(4, PinValue::Temperature(Temperature::new(-127.0)), Local.ymd(2019, 8, 3).and_hms(8, 7, 0), None),
+                    "zone3".to_owned()
+                ));
+                assert_eq!(repository.get_last_temperature_dt("zone3", 4), Some(Local.ymd(2019, 8, 3).and_hms(8, 6, 0)));
+            }
+
+            it "should keep both of two distant readings"
+            {
+                let since = Local.ymd(2019, 8, 3).and_hms(8, 0, 0);
+                for (temperature, minute) in vec![(20.0, 1), (31.0, 2)] {
+                    repository.save_state(&PinOperation::new(
+                        PinState::new(4, PinValue::Temperature(Temperature::new(temperature)), Local.ymd(2019, 8, 3).and_hms(8, minute, 0), None),
+                        "zone3".to_owned()
+                    ));
+                }
+                assert_eq!(repository.get_average_temperature("zone3", 4, &since), Some(Temperature::new(25.5)));
+            }
+
+            it "should follow step change"
+            {
+                let since = Local.ymd(2019, 8, 3).and_hms(8, 0, 0);
+                for (temperature, minute) in vec![(20.0, 1), (20.0, 2), (20.0, 3), (20.0, 4), (31.0, 5), (31.0, 6)] {
+                    repository.save_state(&PinOperation::new(
+                        PinState::new(4, PinValue::Temperature(Temperature::new(temperature)), Local.ymd(2019, 8, 3).and_hms(8, minute, 0), None),
+                        "zone3".to_owned()
+                    ));
+                }
+                assert_eq!(
+                    repository.get_temperatures("zone3", 4, &since).iter().map(|(_, t)| t.value).collect::<Vec<f32>>(),
+                    vec![20.0, 20.0, 20.0, 20.0, 31.0, 31.0]
+                );
+                assert_eq!(repository.get_temperatures("zone3", 4, &Local.ymd(2019, 8, 3).and_hms(8, 3, 0)).len(), 4);
+            }
+
+            it "should keep one pending command per pin"
+            {
+                let now = Local.ymd(2019, 8, 3).and_hms(8, 0, 0);
+                repository.save_command("main", 1, 255, &(now + chrono::Duration::seconds(30)), &now).unwrap();
+                repository.save_command("main", 1, 0, &(now + chrono::Duration::seconds(30)), &now).unwrap();
+                repository.save_command("main", 2, 1, &(now + chrono::Duration::seconds(30)), &now).unwrap();
+                assert_eq!(repository.get_pending_commands().len(), 2);
+
+                let command = repository.get_pending_command("main", 1).expect("command");
+                assert_eq!((command.value, command.attempts, command.last_sent, command.failed), (0, 1, now.naive_local(), false));
+                assert_eq!(command.get_pin_value(), Some((1, 0)));
+                for (pin, value) in vec![(256, 0), (-1, 0), (1, 65536), (1, -1)] {
+                    let invalid = PendingCommand::new("id".to_owned(), "main".to_owned(), pin, value, 1, now.naive_local(), now.naive_local(), now.naive_local());
+                    assert!(invalid.get_pin_value().is_none(), "{} {}", pin, value);
+                }
+                let sent = now + chrono::Duration::seconds(30);
+                repository.update_command_retry(&command.id, 2, &(now + chrono::Duration::seconds(90)), &sent).unwrap();
+                let command = repository.get_pending_command("main", 1).expect("command");
+                assert_eq!((command.attempts, command.next_retry), (2, (now + chrono::Duration::seconds(90)).naive_local()));
+                assert_eq!((command.dtc, command.last_sent), (now.naive_local(), sent.naive_local()));
+                repository.set_command_failed(&command.id).unwrap();
+                assert!(repository.get_pending_command("main", 1).expect("command").failed);
+
+                repository.remove_command("main", 1).unwrap();
+                assert!(repository.get_pending_command("main", 1).is_none());
+                let command = repository.get_pending_command("main", 2).expect("command");
+                repository.remove_command_by_id(&command.id).unwrap();
+                assert!(repository.get_pending_commands().is_empty());
+            }
+
+            it "should aggregate by config"
+            {
+                let since = Local.ymd(2019, 8, 3).and_hms(8, 0, 0);
+                for (temperature, minute) in vec![(20.0, 1), (20.0, 2), (21.0, 3), (24.0, 4)] {
+                    repository.save_state(&PinOperation::new(
+                        PinState::new(4, PinValue::Temperature(Temperature::new(temperature)), Local.ymd(2019, 8, 3).and_hms(8, minute, 0), None),
+                        "zone3".to_owned()
+                    ));
+                }
+                for (aggregation, expected) in vec![("mean", 21.25), ("median", 20.5), ("trimmed_mean", 20.5), ("ema", 22.25)] {
+                    let config = create_settings(&format!("temperature_aggregation: {}\ntemperature_trim: 0.25\ntemperature_ema_alpha: 0.5", aggregation));
+                    let repository = PinStateRepository::new(&connection).with_config(&config);
+                    assert_eq!(repository.get_average_temperature("zone3", 4, &since), Some(Temperature::new(expected)), "{}", aggregation);
+                }
+            }
         }
     }
 }
@@ -19,7 +19,55 @@ table! {
     }
 }
 
+table! {
+    zone_overrides (id) {
+        id -> Text,
+        name -> Text,
+        temperature -> Float,
+        expires -> Timestamp,
+        dtc -> Timestamp,
+    }
+}
+
+table! {
+    events (id) {
+        id -> Text,
+        name -> Text,
+        kind -> Text,
+        message -> Text,
+        dtc -> Timestamp,
+    }
+}
+
+table! {
+    pending_commands (id) {
+        id -> Text,
+        name -> Text,
+        pin -> Integer,
+        value -> Integer,
+        attempts -> Integer,
+        next_retry -> Timestamp,
+        dtc -> Timestamp,
+        last_sent -> Timestamp,
+        failed -> Bool,
+    }
+}
+
+table! {
+    pid_states (id) {
+        id -> Text,
+        name -> Text,
+        integral -> Float,
+        last_error -> Float,
+        last_update -> Timestamp,
+    }
+}
+
 allow_tables_to_appear_in_same_query!(
     pin_states,
     temperatures,
+    zone_overrides,
+    events,
+    pending_commands,
+    pid_states,
 );
@@ -2,18 +2,33 @@ use crate::config::{Zones, ControlNodes, Settings};
 use std::collections::HashMap;
 use arduino_mqtt_pin::pin::PinValue;
 use crate::repository::PinStateRepository;
-use crate::deciders::{HeaterDecider, ZoneStateDecider};
+use crate::deciders::{HeaterDecider, HeaterPhase, WindowOpenDecider, StaleSensorDecider, CycleDecider, ExerciseDecider, HotWaterDecider, PumpDecider};
+use crate::strategies::ZoneDecider;
 use chrono::{DateTime, Local, Duration};
+use log::{info};
 use derive_new::{new};
 
 pub type PinChanges = HashMap<String, HashMap<u8, PinValue>>;
 
+// deciders consulted by the retriever, built by name so a single one can be swapped
+#[derive(Clone, Copy)]
+pub struct Deciders<'a>
+{
+    pub heater: &'a HeaterDecider<'a>,
+    pub zone: &'a dyn ZoneDecider,
+    pub window: &'a WindowOpenDecider<'a>,
+    pub stale: &'a StaleSensorDecider<'a>,
+    pub cycle: &'a CycleDecider<'a>,
+    pub exercise: &'a ExerciseDecider<'a>,
+    pub hot_water: &'a HotWaterDecider<'a>,
+    pub pump: &'a PumpDecider<'a>
+}
+
 #[derive(new)]
 pub struct StateRetriever<'a>
 {
     repository: &'a PinStateRepository<'a>,
-    heater_decider: &'a HeaterDecider<'a>,
-    zone_decider: &'a ZoneStateDecider<'a>,
+    deciders: Deciders<'a>,
     config: &'a Settings
 }
 
@@ -22,10 +37,32 @@ impl StateRetriever<'_>
     pub fn get_zone_pins_to_change(&self, control_name: &str, zones: &Zones, now: &DateTime<Local>) -> HashMap<u8, PinValue>
     {
         let mut zone_changes: HashMap<u8, PinValue> = HashMap::new();
-        for (zone_name, zone) in zones {
-            if let Some(last_state) = self.repository.get_last_changed_pin_state(control_name, zone.control_pin) {
-                if let Some(avg_temp) = self.repository.get_average_temperature(zone_name, zone.sensor_pin, &(*now - Duration::minutes(30))) {
-                    self.zone_decider.get_value_to_change_to(&last_state, zone, &avg_temp, now)
+        for zone in zones.values() {
+            let last_state = self.repository.get_last_pin_state(control_name, zone.control_pin);
+            let is_on = last_state.as_ref().map_or(false, |state| state.is_on());
+            if self.deciders.window.is_window_open(zone, now) {
+                if is_on {
+                    zone_changes.insert(zone.control_pin, PinValue::Analog(0u16));
+                }
+            } else if self.deciders.stale.is_unreported(zone) {
+                // fail safe needs a sensor that worked once, usually the sensor address is wrong
+                self.deciders.stale.report_unreported(zone, now);
+                if is_on {
+                    zone_changes.insert(zone.control_pin, PinValue::Analog(0u16));
+                }
+            } else if self.deciders.stale.is_stale(zone, now) {
+                self.deciders.stale.report_stale(zone, now);
+                let value = self.deciders.stale.get_fail_safe_value(zone, now);
+                let changed = value.is_on() != is_on || last_state.map_or(false, |state| state.is_on() && state.value != value);
+                if changed && (value.is_on() == is_on || self.deciders.cycle.can_switch_zone(control_name, zone, value.is_on(), now)) {
+                    zone_changes.insert(zone.control_pin, value);
+                }
+            } else if let Some(last_state) = last_state {
+                // latest state is required to compare pwm values
+                if let Some(avg_temp) = self.repository.get_average_temperature(&zone.get_sensor_name(), zone.get_sensor_pin(), &(*now - zone.get_temperature_average_time(self.config))) {
+                    // pwm changes while on are not switches
+                    self.deciders.zone.get_value_to_change_to(&last_state, zone, &avg_temp, now)
+                        .filter(|value| value.is_on() == last_state.is_on() || self.deciders.cycle.can_switch_zone(control_name, zone, value.is_on(), now))
                         .map(|value| zone_changes.insert(zone.control_pin, value));
                 } else if last_state.is_on() {
                     zone_changes.insert(zone.control_pin, PinValue::Analog(0u16));
@@ -37,43 +74,136 @@ impl StateRetriever<'_>
 
     pub fn get_pins_expected_to_change(&self, control_nodes: &ControlNodes, now: &DateTime<Local>) -> PinChanges
     {
-        let current_state = self.repository.get_last_changed_pin_state(&self.config.heater_control_name(), self.config.heater_control_pin());
-        if let Some(state) = current_state.clone() {
-            if state.is_on() && self.all_zones_should_be_off(control_nodes, now) {
-                return self.turn_heater(false);
-            } else if !state.is_on() && !self.heater_decider.can_turn_zones_off(&state, now) {
-                return PinChanges::new();
+        for control_node in control_nodes.values() {
+            for zone in control_node.zones.values() {
+                self.deciders.window.record_suspension(zone, now);
             }
         }
+        let has_demand = !self.all_zones_should_be_off(control_nodes, now) || self.deciders.hot_water.should_any_charge(control_nodes, now);
+        let phase = self.deciders.heater.update_phase(control_nodes, has_demand, now);
+        if let Some(exercise_changes) = self.deciders.exercise.get_pins_to_change(control_nodes, phase, has_demand, now) {
+            return exercise_changes;
+        }
+        match phase {
+            HeaterPhase::Firing if !has_demand => {
+                if !self.deciders.cycle.can_switch_heater(false, now) {
+                    return PinChanges::new();
+                }
+                return self.deciders.heater.get_sources_to_change(false, now);
+            },
+            HeaterPhase::PumpOverrun => return PinChanges::new(),
+            _ => {}
+        }
 
+        let priority_charging = self.deciders.hot_water.is_priority_charging(control_nodes, now);
         let mut control_changes: PinChanges = PinChanges::new();
         for (control_name, control_node) in control_nodes {
-            let zone_changes = self.get_zone_pins_to_change(control_name, &control_node.zones, now);
+            let mut zone_changes = self.get_zone_pins_to_change(control_name, &control_node.zones, now);
+            if priority_charging {
+                // closed zones wait for the tank
+                zone_changes.retain(|pin, value| !value.is_on()
+                    || self.repository.get_last_pin_state(control_name, *pin).map_or(false, |state| state.is_on())
+                );
+            }
             if zone_changes.len() > 0 {
                 control_changes.insert(control_name.clone(), zone_changes);
             }
         }
 
+        let mut control_changes = self.limit_open_zones(control_nodes, control_changes, now);
+        for (control_name, tank_changes) in self.deciders.hot_water.get_pins_to_change(control_nodes, now) {
+            control_changes.entry(control_name).or_insert_with(HashMap::new).extend(tank_changes);
+        }
+        for (control_name, pump_changes) in self.deciders.pump.get_pins_to_change(control_nodes, now) {
+            for (pin, value) in pump_changes {
+                if self.deciders.cycle.can_switch_pump(&control_name, pin, value.is_on(), now) {
+                    control_changes.entry(control_name.clone()).or_insert_with(HashMap::new).insert(pin, value);
+                }
+            }
+        }
         if control_changes.len() > 0 {
             return control_changes;
         }
 
-        if let Some(state) = current_state {
-            if !state.is_on() && self.heater_decider.should_be_on(control_nodes, now) {
-                return self.turn_heater(true);
-            }
+        match phase {
+            HeaterPhase::WarmingActuators if self.deciders.heater.should_be_on(control_nodes, now) && self.deciders.cycle.can_switch_heater(true, now) =>
+                self.deciders.heater.get_sources_to_change(true, now),
+            // next stages join and disabled sources hand over while the heater runs
+            HeaterPhase::Firing => self.deciders.heater.get_sources_to_change(true, now),
+            // a heat source found on outside firing is turned off
+            _ => self.deciders.heater.get_sources_to_change(false, now)
         }
+    }
 
-        PinChanges::new()
+    // zones waiting to open are ordered by priority and then by how long they have been closed,
+    // after open_zone_slice_time a waiting zone takes the slot of the longest open zone of lower or equal priority
+    fn limit_open_zones(&self, control_nodes: &ControlNodes, mut control_changes: PinChanges, now: &DateTime<Local>) -> PinChanges
+    {
+        let max_open_zones = match self.config.max_open_zones() {
+            Some(max) => max as usize,
+            None => return control_changes
+        };
+        let slice = Duration::seconds(self.config.open_zone_slice_time() as i64);
+        let mut open = Vec::new();
+        let mut waiting = Vec::new();
+        for (control_name, control_node) in control_nodes {
+            for zone in control_node.zones.values() {
+                let is_on = self.repository.get_last_pin_state(control_name, zone.control_pin).map_or(false, |state| state.is_on());
+                let change = control_changes.get(control_name).and_then(|pins| pins.get(&zone.control_pin)).map(|value| value.is_on());
+                let changed_at = self.repository.get_last_changed_pin_state(control_name, zone.control_pin).map(|state| state.dt);
+                match (is_on, change) {
+                    (true, Some(false)) => {},
+                    (true, _) => open.push((zone, changed_at, control_name)),
+                    (false, Some(true)) => waiting.push((zone, changed_at, control_name)),
+                    _ => {}
+                }
+            }
+        }
+        waiting.sort_by(|a, b| b.0.priority.cmp(&a.0.priority).then(a.1.cmp(&b.1)));
+        open.sort_by(|a, b| a.1.cmp(&b.1));
+        let lasted = |since: &Option<DateTime<Local>>| since.map_or(true, |dt| *now - dt >= slice);
+        for (zone, closed_since, control_name) in waiting.iter().skip(max_open_zones.saturating_sub(open.len())) {
+            let slot = if lasted(closed_since) {
+                open.iter().position(|(open_zone, opened_since, open_control_name)| open_zone.priority <= zone.priority
+                    && lasted(opened_since)
+                    && self.deciders.cycle.can_switch_zone(open_control_name, open_zone, false, now)
+                )
+            } else {
+                None
+            };
+            match slot {
+                Some(i) => {
+                    let (open_zone, _, open_control_name) = open.remove(i);
+                    info!("Zone {} gives its slot to waiting zone {}", open_zone.name, zone.name);
+                    control_changes.entry(open_control_name.clone()).or_insert_with(HashMap::new).insert(open_zone.control_pin, PinValue::Analog(0u16));
+                },
+                None => {
+                    if let Some(pins) = control_changes.get_mut(*control_name) {
+                        pins.remove(&zone.control_pin);
+                    }
+                }
+            }
+        }
+        control_changes.retain(|_, pins| pins.len() > 0);
+        control_changes
     }
 
     fn all_zones_should_be_off(&self, control_nodes: &ControlNodes, now: &DateTime<Local>) -> bool
     {
         for (control_name, control_node) in control_nodes {
-            for (zone_name, zone) in &control_node.zones {
+            for zone in control_node.zones.values() {
+                if self.deciders.window.is_window_open(zone, now) {
+                    continue;
+                }
+                if self.deciders.stale.is_stale(zone, now) {
+                    if self.deciders.stale.get_fail_safe_value(zone, now).is_on() {
+                        return false;
+                    }
+                    continue;
+                }
                 if let Some(last_state) = self.repository.get_last_changed_pin_state(control_name, zone.control_pin) {
-                    if let Some(avg_temp) = self.repository.get_average_temperature(zone_name, zone.sensor_pin, &(*now - Duration::minutes(30))) {
-                        if self.zone_decider.should_be_on(&last_state, zone, &avg_temp, &now) {
+                    if let Some(avg_temp) = self.repository.get_average_temperature(&zone.get_sensor_name(), zone.get_sensor_pin(), &(*now - zone.get_temperature_average_time(self.config))) {
+                        if self.deciders.zone.should_be_on(&last_state, zone, &avg_temp, &now) {
                             return false;
                         }
                     }
@@ -82,19 +212,6 @@ impl StateRetriever<'_>
         }
         true
     }
-
-    fn turn_heater(&self, value: bool) -> PinChanges
-    {
-        let mut control_changes: PinChanges = PinChanges::new();
-        control_changes.entry(self.config.heater_control_name()).and_modify(|zone_changes| {
-            zone_changes.insert(self.config.heater_control_pin(), PinValue::Digital(value));
-        }).or_insert_with(|| {
-            let mut zone_changes = HashMap::new();
-            zone_changes.insert(self.config.heater_control_pin(), PinValue::Digital(value));
-            zone_changes
-        });
-        control_changes
-    }
 }
 
 #[cfg(test)]
@@ -102,12 +219,17 @@ mod test_deciders
 {
     use super::*;
     use chrono::{TimeZone};
-    use crate::repository::test_repository::{create_nodes, create_repository};
-    use crate::deciders::{TemperatureStateDecider, HeaterDecider, ZoneStateDecider};
+    use crate::repository::test_repository::{create_nodes, create_repository, save_pin, save_temperature};
+    use crate::deciders::{TemperatureStateDecider, HeaterDecider, ZoneStateDecider, WindowOpenDecider, StaleSensorDecider, CycleDecider, ExerciseDecider, HotWaterDecider, PumpDecider};
     use arduino_mqtt_pin::pin::{PinState, PinOperation, Temperature};
     use crate::config::{Config};
+    use crate::zone::HotWaterZone;
+    use crate::repository::{EVENT_SENSOR_STALE, EVENT_SENSOR_UNREPORTED};
+    use arduino_mqtt_pin::helper::percent_to_analog;
+    use crate::config::tests::create_settings;
     use diesel::{SqliteConnection, Connection};
     use crate::embedded_migrations;
+    use crate::clock::{Clock, ManualClock};
 
     speculate! {
         describe "state changes"
@@ -117,11 +239,27 @@ mod test_deciders
                 let connection = SqliteConnection::establish(":memory:").unwrap();
                 embedded_migrations::run(&connection);
                 let config = Settings::new(Config::new("test".to_owned(), "host".to_owned(), "main".to_owned(), 34));
-                let temp_decider = TemperatureStateDecider::new(&config);
                 let repository = PinStateRepository::new(&connection);
+                let temp_decider = TemperatureStateDecider::new(&repository, &config);
                 let heater_decider = HeaterDecider::new(&repository, &config);
-                let zone_decider = ZoneStateDecider::new(&temp_decider, &config);
-                let state_retriever = StateRetriever::new(&repository, &heater_decider, &zone_decider, &config);
+                let zone_decider = ZoneStateDecider::new(&repository, &temp_decider, &config);
+                let window_decider = WindowOpenDecider::new(&repository, &config);
+                let stale_decider = StaleSensorDecider::new(&repository, &config);
+                let cycle_decider = CycleDecider::new(&repository, &config);
+                let exercise_decider = ExerciseDecider::new(&repository, &cycle_decider, &config);
+                let hot_water_decider = HotWaterDecider::new(&repository, &config);
+                let pump_decider = PumpDecider::new(&repository, &config);
+                let deciders = Deciders {
+                    heater: &heater_decider,
+                    zone: &zone_decider,
+                    window: &window_decider,
+                    stale: &stale_decider,
+                    cycle: &cycle_decider,
+                    exercise: &exercise_decider,
+                    hot_water: &hot_water_decider,
+                    pump: &pump_decider
+                };
+                let state_retriever = StateRetriever::new(&repository, deciders, &config);
             }
 
             it "should be off while no zones are on"
@@ -212,6 +350,208 @@ mod test_deciders
                 let pins = state_retriever.get_pins_expected_to_change(&nodes, &Local.ymd(2019, 8, 2).and_hms(23, 9, 2));
                 assert_eq!(pins, expected);
             }
+
+            it "should limit open zones"
+            {
+                let mut nodes = create_nodes();
+                let config = create_settings("max_open_zones: 1");
+                let exercise_decider = ExerciseDecider::new(&repository, &cycle_decider, &config);
+                let state_retriever = StateRetriever::new(&repository, Deciders { exercise: &exercise_decider, ..deciders }, &config);
+                for (node, pin, value, dt) in vec![
+                    ("main", 34, PinValue::Digital(false), Local.ymd(2019, 8, 2).and_hms(8, 0, 0)),
+                    ("main", 1, PinValue::Analog(0), Local.ymd(2019, 8, 2).and_hms(9, 0, 0)),
+                    ("main", 2, PinValue::Analog(0), Local.ymd(2019, 8, 2).and_hms(8, 0, 0)),
+                    ("zone1", 4, PinValue::Temperature(Temperature::new(20.5)), Local.ymd(2019, 8, 2).and_hms(22, 50, 0)),
+                    ("zone2", 4, PinValue::Temperature(Temperature::new(20.5)), Local.ymd(2019, 8, 2).and_hms(22, 50, 0)),
+                ] {
+                    repository.save_state(&PinOperation::new(PinState::new(pin, value, dt, None), node.to_owned()));
+                }
+
+                // zone closed for longer goes first
+                let expected: PinChanges = map!{ "main".to_owned() => map!{ 2 => PinValue::Analog(1023) }};
+                let pins = state_retriever.get_pins_expected_to_change(&nodes, &Local.ymd(2019, 8, 2).and_hms(23, 2, 1));
+                assert_eq!(pins, expected);
+
+                nodes.get_mut("main").unwrap().zones.get_mut("zone1").unwrap().priority = 1;
+                let expected: PinChanges = map!{ "main".to_owned() => map!{ 1 => PinValue::Analog(1023) }};
+                let pins = state_retriever.get_pins_expected_to_change(&nodes, &Local.ymd(2019, 8, 2).and_hms(23, 2, 1));
+                assert_eq!(pins, expected);
+
+                repository.save_state(&PinOperation::new(
+                    PinState::new(2, PinValue::Analog(1023), Local.ymd(2019, 8, 2).and_hms(23, 2, 2), None),
+                    "main".to_owned()
+                ));
+                let pins = state_retriever.get_pins_expected_to_change(&nodes, &Local.ymd(2019, 8, 2).and_hms(23, 3, 0));
+                assert_eq!(pins.len(), 0, "{:?}", pins);
+            }
+
+            it "should rotate open zones after slice time"
+            {
+                let nodes = create_nodes();
+                let config = create_settings("max_open_zones: 1\nopen_zone_slice_time: 600");
+                let exercise_decider = ExerciseDecider::new(&repository, &cycle_decider, &config);
+                let state_retriever = StateRetriever::new(&repository, Deciders { exercise: &exercise_decider, ..deciders }, &config);
+                save_pin(&repository, "main", 34, PinValue::Digital(false), Local.ymd(2019, 8, 2).and_hms(8, 0, 0));
+                save_pin(&repository, "main", 1, PinValue::Analog(1023), Local.ymd(2019, 8, 2).and_hms(22, 0, 0));
+                save_pin(&repository, "main", 2, PinValue::Analog(0), Local.ymd(2019, 8, 2).and_hms(22, 0, 0));
+                save_temperature(&repository, "zone1", 4, 20.5, Local.ymd(2019, 8, 2).and_hms(22, 50, 0));
+                save_temperature(&repository, "zone2", 4, 20.5, Local.ymd(2019, 8, 2).and_hms(22, 50, 0));
+
+                // both zones have demand, zone2 has waited longer than the slice
+                let pins = state_retriever.get_pins_expected_to_change(&nodes, &Local.ymd(2019, 8, 2).and_hms(23, 2, 1));
+                assert_eq!(pins["main"].get(&1), Some(&PinValue::Analog(0)));
+                assert_eq!(pins["main"].get(&2), Some(&PinValue::Analog(1023)));
+                save_pin(&repository, "main", 1, PinValue::Analog(0), Local.ymd(2019, 8, 2).and_hms(23, 2, 2));
+                save_pin(&repository, "main", 2, PinValue::Analog(1023), Local.ymd(2019, 8, 2).and_hms(23, 2, 2));
+
+                let pins = state_retriever.get_pins_expected_to_change(&nodes, &Local.ymd(2019, 8, 2).and_hms(23, 5, 0));
+                assert!(pins.get("main").map_or(true, |pins| !pins.contains_key(&1) && !pins.contains_key(&2)), "{:?}", pins);
+
+                let pins = state_retriever.get_pins_expected_to_change(&nodes, &Local.ymd(2019, 8, 2).and_hms(23, 12, 3));
+                assert_eq!(pins["main"].get(&1), Some(&PinValue::Analog(1023)));
+                assert_eq!(pins["main"].get(&2), Some(&PinValue::Analog(0)));
+            }
+
+            it "should charge hot water before zones"
+            {
+                let mut nodes = create_nodes();
+                let mut tank = HotWaterZone::new("boiler".to_owned(), 3, 12, Temperature::new(55.0));
+                tank.priority = true;
+                nodes.get_mut("main").unwrap().hot_water = Some(tank);
+                for (node, pin, value, dt) in vec![
+                    ("main", 34, PinValue::Digital(false), Local.ymd(2019, 8, 2).and_hms(8, 0, 0)),
+                    ("main", 12, PinValue::Digital(false), Local.ymd(2019, 8, 2).and_hms(8, 0, 0)),
+                    ("main", 1, PinValue::Analog(0), Local.ymd(2019, 8, 2).and_hms(9, 0, 0)),
+                    ("zone1", 4, PinValue::Temperature(Temperature::new(20.5)), Local.ymd(2019, 8, 2).and_hms(22, 50, 0)),
+                    ("boiler", 3, PinValue::Temperature(Temperature::new(40.0)), Local.ymd(2019, 8, 2).and_hms(22, 55, 0)),
+                ] {
+                    repository.save_state(&PinOperation::new(PinState::new(pin, value, dt, None), node.to_owned()));
+                }
+
+                let expected: PinChanges = map!{ "main".to_owned() => map!{ 12 => PinValue::Digital(true) }};
+                let pins = state_retriever.get_pins_expected_to_change(&nodes, &Local.ymd(2019, 8, 2).and_hms(23, 2, 1));
+                assert_eq!(pins, expected);
+
+                repository.save_state(&PinOperation::new(
+                    PinState::new(12, PinValue::Digital(true), Local.ymd(2019, 8, 2).and_hms(23, 2, 2), None),
+                    "main".to_owned()
+                ));
+                let expected: PinChanges = map!{ "main".to_owned() => map!{ 34 => PinValue::Digital(true) }};
+                let pins = state_retriever.get_pins_expected_to_change(&nodes, &Local.ymd(2019, 8, 2).and_hms(23, 3, 0));
+                assert_eq!(pins, expected);
+            }
+
+            it "should run a whole day on manual clock"
+            {
+                let nodes = create_nodes();
+                let clock = ManualClock::new(Local.ymd(2019, 8, 2).and_hms(0, 0, 0));
+                for (pin, value) in vec![(1, PinValue::Analog(0)), (2, PinValue::Analog(0)), (4, PinValue::Analog(0)), (34, PinValue::Digital(false))] {
+                    repository.save_state(&PinOperation::new(PinState::new(pin, value, clock.now(), None), "main".to_owned()));
+                }
+                let mut temperatures: HashMap<String, f32> = nodes["main"].zones.keys().map(|name| (name.clone(), 18.0)).collect();
+                let mut zone_opened = None;
+                let mut heater_started = None;
+                while clock.now() < Local.ymd(2019, 8, 3).and_hms(0, 0, 0) {
+                    let now = clock.now();
+                    // rooms warm up while valves are open and cool down to 18 otherwise
+                    for (name, zone) in &nodes["main"].zones {
+                        let is_on = repository.get_last_pin_state("main", zone.control_pin).map_or(false, |state| state.is_on());
+                        let temperature = temperatures.get_mut(name).unwrap();
+                        *temperature = if is_on { *temperature + 0.1 } else { (*temperature - 0.1).max(18.0) };
+                        repository.save_state(&PinOperation::new(
+                            PinState::new(zone.sensor_pin, PinValue::Temperature(Temperature::new(*temperature)), now, None),
+                            name.clone()
+                        ));
+                    }
+                    // nodes acknowledge every change
+                    for (control_name, pins) in state_retriever.get_pins_expected_to_change(&nodes, &now) {
+                        for (pin, value) in pins {
+                            if pin == 1 && value.is_on() && zone_opened.is_none() {
+                                zone_opened = Some(now);
+                            }
+                            if pin == 34 && value.is_on() && heater_started.is_none() {
+                                heater_started = Some(now);
+                            }
+                            repository.save_state(&PinOperation::new(PinState::new(pin, value, now, None), control_name.clone()));
+                        }
+                    }
+                    clock.advance(chrono::Duration::minutes(5));
+                }
+
+                let zone_opened = zone_opened.expect("zone opened");
+                assert!(zone_opened >= Local.ymd(2019, 8, 2).and_hms(8, 0, 0) && zone_opened < Local.ymd(2019, 8, 2).and_hms(9, 0, 0), "{}", zone_opened);
+                assert!(heater_started.expect("heater started") > zone_opened);
+                assert_eq!(clock.now(), Local.ymd(2019, 8, 3).and_hms(0, 0, 0));
+            }
+
+            it "should use fail safe for stale sensors"
+            {
+                let nodes = create_nodes();
+                let repository = create_repository(&connection);
+                let config = create_settings("sensor_stale_timeout: 600\nfail_safe: {mode: pwm, power: 50}");
+                let stale_decider = StaleSensorDecider::new(&repository, &config);
+                let state_retriever = StateRetriever::new(&repository, Deciders { stale: &stale_decider, ..deciders }, &config);
+                let fail_safe = percent_to_analog(50);
+
+                // zone1 reported at 8:55
+                let pins = state_retriever.get_zone_pins_to_change("main", &nodes["main"].zones, &Local.ymd(2019, 8, 2).and_hms(8, 59, 0));
+                assert_eq!(pins.get(&2), Some(&PinValue::Analog(fail_safe)));
+                assert_eq!(pins.get(&4), Some(&PinValue::Analog(fail_safe)));
+                assert_ne!(pins.get(&1), Some(&PinValue::Analog(fail_safe)));
+
+                assert!(!state_retriever.all_zones_should_be_off(&nodes, &Local.ymd(2019, 8, 2).and_hms(10, 0, 0)));
+                let expected: HashMap<u8, PinValue> = map!{ 1 => PinValue::Analog(fail_safe), 2 => PinValue::Analog(fail_safe), 4 => PinValue::Analog(fail_safe) };
+                let pins = state_retriever.get_zone_pins_to_change("main", &nodes["main"].zones, &Local.ymd(2019, 8, 2).and_hms(10, 0, 0));
+                assert_eq!(pins, expected);
+                state_retriever.get_zone_pins_to_change("main", &nodes["main"].zones, &Local.ymd(2019, 8, 2).and_hms(10, 5, 0));
+                let event = repository.get_last_event("zone1", EVENT_SENSOR_STALE).expect("alarm");
+                assert_eq!(event.dtc, Local.ymd(2019, 8, 2).and_hms(10, 0, 0).naive_local());
+            }
+
+            it "should keep zones without readings closed"
+            {
+                let nodes = create_nodes();
+                let config = create_settings("sensor_stale_timeout: 600\nfail_safe: {mode: pwm, power: 50}");
+                let stale_decider = StaleSensorDecider::new(&repository, &config);
+                let state_retriever = StateRetriever::new(&repository, Deciders { stale: &stale_decider, ..deciders }, &config);
+                for (pin, value) in vec![(1, PinValue::Analog(1023)), (2, PinValue::Analog(0))] {
+                    repository.save_state(&PinOperation::new(PinState::new(pin, value, Local.ymd(2019, 8, 2).and_hms(8, 0, 0), None), "main".to_owned()));
+                }
+
+                let now = Local.ymd(2019, 8, 2).and_hms(10, 0, 0);
+                let expected: HashMap<u8, PinValue> = map!{ 1 => PinValue::Analog(0) };
+                let pins = state_retriever.get_zone_pins_to_change("main", &nodes["main"].zones, &now);
+                assert_eq!(pins, expected);
+                assert!(state_retriever.all_zones_should_be_off(&nodes, &now));
+                assert!(repository.get_last_event("zone1", EVENT_SENSOR_STALE).is_none());
+                let event = repository.get_last_event("zone1", EVENT_SENSOR_UNREPORTED).expect("alarm");
+                assert_eq!(event.dtc, now.naive_local());
+            }
+
+            it "should close zone with open window"
+            {
+                let nodes = create_nodes();
+                let repository = create_repository(&connection);
+                let config = create_settings("window_open_drop_rate: 0.2");
+                let window_decider = WindowOpenDecider::new(&repository, &config);
+                let state_retriever = StateRetriever::new(&repository, Deciders { window: &window_decider, ..deciders }, &config);
+                assert!(!state_retriever.all_zones_should_be_off(&nodes, &Local.ymd(2019, 8, 2).and_hms(8, 20, 0)));
+
+                for (temperature, minute) in vec![(20.0, 13), (18.5, 19)] {
+                    repository.save_state(&PinOperation::new(
+                        PinState::new(4, PinValue::Temperature(Temperature::new(temperature)), Local.ymd(2019, 8, 2).and_hms(8, minute, 0), None),
+                        "zone1".to_owned()
+                    ));
+                }
+
+                let expected: PinChanges = map!{ "main".to_owned() => map!{ 34 =>  PinValue::Digital(false) }};
+                let pins = state_retriever.get_pins_expected_to_change(&nodes, &Local.ymd(2019, 8, 2).and_hms(8, 20, 0));
+                assert_eq!(pins, expected);
+
+                let expected: HashMap<u8, PinValue> = map!{ 1 => PinValue::Analog(0) };
+                let pins = state_retriever.get_zone_pins_to_change("main", &nodes["main"].zones, &Local.ymd(2019, 8, 2).and_hms(8, 25, 0));
+                assert_eq!(pins, expected);
+            }
         }
     }
 }
\ No newline at end of file
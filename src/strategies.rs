@@ -0,0 +1,396 @@
+use chrono::{DateTime, Local, Timelike};
+use arduino_mqtt_pin::pin::{PinState, PinValue, Temperature};
+use arduino_mqtt_pin::helper::percent_to_analog;
+use crate::config::Settings;
+use crate::deciders::TemperatureStateDecider;
+use crate::repository::PinStateRepository;
+use crate::zone::{Zone, PidSettings};
+use derive_new::{new};
+use log::{warn};
+
+// pid output changes smaller than this are not sent, in percent
+const PID_MIN_CHANGE: u8 = 5;
+// longer gaps between evaluations are not integrated, in minutes
+const PID_MAX_INTERVAL: f32 = 10.0;
+// used when zone has pid strategy without pid settings
+const PID_DEFAULT_KP: f32 = 50.0;
+const PID_DEFAULT_KI: f32 = 0.5;
+
+pub trait ZoneDecider
+{
+    // zone requires heating
+    fn should_be_on(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> bool;
+
+    // value for zone control pin if it needs to change
+    fn get_value_to_change_to(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> Option<PinValue>;
+}
+
+// once on zone stays on until expected temperature is reached
+// once off zone waits for temperature to drop its temperature_drop_wait below expected
+fn has_heat_demand(temp_decider: &TemperatureStateDecider, config: &Settings, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> bool
+{
+    let expected_temperature = temp_decider.get_expected_temperature(zone, now);
+    if last_state.is_on() {
+        *current_temperature < expected_temperature
+    } else {
+        *current_temperature < expected_temperature - Temperature::new(zone.get_temperature_drop_wait(config))
+    }
+}
+
+#[derive(new)]
+pub struct HysteresisStrategy<'a>
+{
+    temp_decider: &'a TemperatureStateDecider<'a>,
+    config: &'a Settings
+}
+
+impl ZoneDecider for HysteresisStrategy<'_>
+{
+    fn should_be_on(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> bool
+    {
+        has_heat_demand(self.temp_decider, self.config, last_state, zone, current_temperature, now)
+    }
+
+    fn get_value_to_change_to(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> Option<PinValue>
+    {
+        let zone_should_be_on = self.should_be_on(last_state, zone, current_temperature, now);
+        if last_state.is_on() && !zone_should_be_on {
+            return Some(PinValue::Analog(0u16));
+        }
+        if !last_state.is_on() && zone_should_be_on {
+            return Some(PinValue::Analog(percent_to_analog(100)));
+        }
+        None
+    }
+}
+
+#[derive(new)]
+pub struct ProportionalStrategy<'a>
+{
+    temp_decider: &'a TemperatureStateDecider<'a>,
+    config: &'a Settings
+}
+
+impl ZoneDecider for ProportionalStrategy<'_>
+{
+    fn should_be_on(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> bool
+    {
+        has_heat_demand(self.temp_decider, self.config, last_state, zone, current_temperature, now)
+    }
+
+    fn get_value_to_change_to(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> Option<PinValue>
+    {
+        let zone_should_be_on = self.should_be_on(last_state, zone, current_temperature, now);
+        if last_state.is_on() && !zone_should_be_on {
+            return Some(PinValue::Analog(0u16));
+        }
+        if !last_state.is_on() && zone_should_be_on {
+            return Some(self.temp_decider.get_expected_value(current_temperature, zone, now));
+        }
+        None
+    }
+}
+
+#[derive(new)]
+pub struct PidStrategy<'a>
+{
+    repository: &'a PinStateRepository<'a>,
+    temp_decider: &'a TemperatureStateDecider<'a>,
+    config: &'a Settings
+}
+
+impl PidStrategy<'_>
+{
+    // integral state is stored until the zone is turned off so it survives restarts and config reloads
+    pub fn get_expected_value(&self, current_temperature: &Temperature, zone: &Zone, now: &DateTime<Local>) -> PinValue
+    {
+        let expected_temperature = self.temp_decider.get_expected_temperature(zone, now);
+        if *current_temperature >= expected_temperature {
+            return PinValue::Analog(0);
+        }
+        let pid = zone.pid.clone().unwrap_or_else(|| PidSettings::new(PID_DEFAULT_KP, PID_DEFAULT_KI, 0f32));
+        let error = expected_temperature.value - current_temperature.value;
+        let (last_integral, last_error, minutes) = match self.repository.get_pid_state(&zone.name) {
+            Some(state) => {
+                let seconds = (now.naive_local() - state.last_update).num_seconds();
+                (state.integral, state.last_error, (seconds as f32 / 60f32).max(0f32).min(PID_MAX_INTERVAL))
+            },
+            None => (0f32, error, 0f32)
+        };
+        let derivative = if minutes > 0f32 { (error - last_error) / minutes } else { 0f32 };
+        let mut integral = last_integral + error * minutes;
+        // anti windup
+        if pid.ki > 0f32 {
+            integral = integral.max(0f32).min(100f32 / pid.ki);
+        }
+        if let Err(e) = self.repository.save_pid_state(&zone.name, integral, error, now) {
+            warn!("Unable to save pid state for {} {:?}", zone.name, e);
+        }
+
+        let output = pid.kp * error + pid.ki * integral + pid.kd * derivative;
+        let percent = output.max(zone.get_min_pwm_state(self.config) as f32).min(100f32);
+        PinValue::Analog(percent_to_analog(percent as u8))
+    }
+
+    pub fn reset_state(&self, zone: &Zone)
+    {
+        if let Err(e) = self.repository.remove_pid_state(&zone.name) {
+            warn!("Unable to remove pid state for {} {:?}", zone.name, e);
+        }
+    }
+}
+
+impl ZoneDecider for PidStrategy<'_>
+{
+    fn should_be_on(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> bool
+    {
+        has_heat_demand(self.temp_decider, self.config, last_state, zone, current_temperature, now)
+    }
+
+    // value is re-evaluated while the zone stays on
+    fn get_value_to_change_to(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> Option<PinValue>
+    {
+        let zone_should_be_on = self.should_be_on(last_state, zone, current_temperature, now);
+        if !zone_should_be_on {
+            self.reset_state(zone);
+            return if last_state.is_on() { Some(PinValue::Analog(0u16)) } else { None };
+        }
+        let value = self.get_expected_value(current_temperature, zone, now);
+        if !last_state.is_on() {
+            return Some(value);
+        }
+        let last_value = last_state.value.as_u16();
+        let diff = if value.as_u16() > last_value { value.as_u16() - last_value } else { last_value - value.as_u16() };
+        if diff >= percent_to_analog(PID_MIN_CHANGE) {
+            return Some(value);
+        }
+        None
+    }
+}
+
+#[derive(new)]
+pub struct TimeProportionalStrategy<'a>
+{
+    temp_decider: &'a TemperatureStateDecider<'a>,
+    config: &'a Settings
+}
+
+impl TimeProportionalStrategy<'_>
+{
+    // zone is fully open for the first part of each cycle
+    fn is_open_phase(&self, current_temperature: &Temperature, zone: &Zone, now: &DateTime<Local>) -> bool
+    {
+        let fraction = self.temp_decider.get_expected_value(current_temperature, zone, now).as_u16() as f32 / percent_to_analog(100) as f32;
+        let cycle = zone.get_time_proportional_cycle(self.config) as u32;
+        if cycle == 0 {
+            return fraction > 0f32;
+        }
+        ((now.num_seconds_from_midnight() % cycle) as f32) < fraction * cycle as f32
+    }
+}
+
+impl ZoneDecider for TimeProportionalStrategy<'_>
+{
+    // heater follows the demand while the zone cycles
+    fn should_be_on(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> bool
+    {
+        has_heat_demand(self.temp_decider, self.config, last_state, zone, current_temperature, now)
+    }
+
+    fn get_value_to_change_to(&self, last_state: &PinState, zone: &Zone, current_temperature: &Temperature, now: &DateTime<Local>) -> Option<PinValue>
+    {
+        let open = self.should_be_on(last_state, zone, current_temperature, now)
+            && self.is_open_phase(current_temperature, zone, now);
+        if last_state.is_on() && !open {
+            return Some(PinValue::Analog(0u16));
+        }
+        if !last_state.is_on() && open {
+            return Some(PinValue::Analog(percent_to_analog(100)));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test_strategies
+{
+    use super::*;
+    use chrono::{TimeZone, NaiveTime};
+    use crate::repository::PinStateRepository;
+    use crate::zone::{Interval, Strategy};
+    use crate::config::tests::{create_fixture, create_settings};
+    use crate::deciders::ZoneStateDecider;
+
+    fn create_zone() -> Zone
+    {
+        let intervals = vec![
+            Interval::new(NaiveTime::from_hms(8, 0, 0), NaiveTime::from_hms(9, 0, 0), Temperature::new(20.0)),
+        ];
+        let mut zone = Zone::new(String::from("zone1"), 1, intervals, 2);
+        zone.pid = Some(PidSettings::new(50.0, 5.0, 0.0));
+        zone
+    }
+
+    // last value, current temperature, minute after 8:00, expected values for
+    // hysteresis, proportional, pid, time proportional
+    fn fixtures() -> Vec<(u16, f32, u32, [Option<u16>; 4])>
+    {
+        let full = Some(percent_to_analog(100));
+        vec![
+            (0, 19.0, 0, [full, full, Some(percent_to_analog(50)), full]),
+            (0, 19.5, 0, [None, None, None, None]),
+            (0, 19.3, 10, [full, Some(percent_to_analog(70)), Some(percent_to_analog(35)), full]),
+            (1023, 20.0, 0, [Some(0), Some(0), Some(0), Some(0)]),
+            (1023, 19.8, 0, [None, None, Some(percent_to_analog(30)), None]),
+            (1023, 19.8, 10, [None, None, Some(percent_to_analog(30)), Some(0)]),
+            (1023, 19.0, 10, [None, None, Some(percent_to_analog(50)), None]),
+            (0, 19.0, 70, [None, None, None, None]),
+            (1023, 19.0, 70, [Some(0), Some(0), Some(0), Some(0)]),
+        ]
+    }
+
+    speculate! {
+        describe "zone strategies"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("time_proportional_cycle: 1200");
+                let repository = PinStateRepository::new(&connection);
+                let temp_decider = TemperatureStateDecider::new(&repository, &config);
+                let zone = create_zone();
+            }
+
+            it "should decide values for each strategy"
+            {
+                for (last_value, temperature, minute, expected) in fixtures() {
+                    // pid state is stored so it must not carry over between fixtures
+                    repository.remove_pid_state(&zone.name).unwrap();
+                    let strategies: Vec<Box<dyn ZoneDecider>> = vec![
+                        Box::new(HysteresisStrategy::new(&temp_decider, &config)),
+                        Box::new(ProportionalStrategy::new(&temp_decider, &config)),
+                        Box::new(PidStrategy::new(&repository, &temp_decider, &config)),
+                        Box::new(TimeProportionalStrategy::new(&temp_decider, &config)),
+                    ];
+                    let now = Local.ymd(2019, 8, 1).and_hms(8, 0, 0) + chrono::Duration::minutes(minute as i64);
+                    let last_state = PinState::new(2, PinValue::Analog(last_value), Local.ymd(2019, 8, 1).and_hms(7, 0, 0), None);
+                    for (i, strategy) in strategies.iter().enumerate() {
+                        assert_eq!(
+                            strategy.get_value_to_change_to(&last_state, &zone, &Temperature::new(temperature), &now),
+                            expected[i].map(|v| PinValue::Analog(v)),
+                            "strategy {} last value {} temperature {} minute {}", i, last_value, temperature, minute
+                        );
+                    }
+                }
+            }
+
+            it "should agree on heat demand"
+            {
+                let strategies: Vec<Box<dyn ZoneDecider>> = vec![
+                    Box::new(HysteresisStrategy::new(&temp_decider, &config)),
+                    Box::new(ProportionalStrategy::new(&temp_decider, &config)),
+                    Box::new(PidStrategy::new(&repository, &temp_decider, &config)),
+                    Box::new(TimeProportionalStrategy::new(&temp_decider, &config)),
+                ];
+                for (last_value, temperature, minute, _) in fixtures() {
+                    let now = Local.ymd(2019, 8, 1).and_hms(8, 0, 0) + chrono::Duration::minutes(minute as i64);
+                    let last_state = PinState::new(2, PinValue::Analog(last_value), Local.ymd(2019, 8, 1).and_hms(7, 0, 0), None);
+                    let demand: Vec<bool> = strategies.iter()
+                        .map(|strategy| strategy.should_be_on(&last_state, &zone, &Temperature::new(temperature), &now))
+                        .collect();
+                    assert!(demand.iter().all(|d| *d == demand[0]), "{:?} last value {} temperature {} minute {}", demand, last_value, temperature, minute);
+                }
+            }
+
+            it "should select strategy by zone"
+            {
+                let zone_decider = ZoneStateDecider::new(&repository, &temp_decider, &config);
+                let last_state = PinState::new(2, PinValue::Analog(0), Local.ymd(2019, 8, 1).and_hms(7, 0, 0), None);
+                let now = Local.ymd(2019, 8, 1).and_hms(8, 10, 0);
+                let mut zone = create_zone();
+                for (strategy, expected) in vec![
+                    (Strategy::Hysteresis, percent_to_analog(100)),
+                    (Strategy::Proportional, percent_to_analog(70)),
+                    (Strategy::Pid, percent_to_analog(35)),
+                    (Strategy::TimeProportional, percent_to_analog(100)),
+                ] {
+                    zone.strategy = Some(strategy);
+                    assert_eq!(
+                        zone_decider.get_value_to_change_to(&last_state, &zone, &Temperature::new(19.3), &now),
+                        Some(PinValue::Analog(expected)),
+                        "{:?}", strategy
+                    );
+                }
+            }
+        }
+
+        describe "pid strategy"
+        {
+            before
+            {
+                let (connection, config) = create_fixture("");
+                let repository = PinStateRepository::new(&connection);
+                let temp_decider = TemperatureStateDecider::new(&repository, &config);
+                let strategy = PidStrategy::new(&repository, &temp_decider, &config);
+                let zone = create_zone();
+                let current_temperature = Temperature::new(19.5);
+            }
+
+            it "should integrate error between calls"
+            {
+                for (expected, minute) in vec![
+                    (30, 0),
+                    (50, 10),
+                    // gap longer than PID_MAX_INTERVAL
+                    (75, 40),
+                    (100, 50),
+                ] {
+                    assert_eq!(
+                        strategy.get_expected_value(&current_temperature, &zone, &Local.ymd(2019, 8, 1).and_hms(8, minute, 0)),
+                        PinValue::Analog(percent_to_analog(expected)),
+                        "minute {}", minute
+                    );
+                }
+                strategy.reset_state(&zone);
+                assert_eq!(
+                    strategy.get_expected_value(&current_temperature, &zone, &Local.ymd(2019, 8, 1).and_hms(8, 55, 0)),
+                    PinValue::Analog(percent_to_analog(30))
+                );
+            }
+
+            it "should keep integral after reload"
+            {
+                for (expected, minute) in vec![(30, 0), (50, 10)] {
+                    assert_eq!(
+                        strategy.get_expected_value(&current_temperature, &zone, &Local.ymd(2019, 8, 1).and_hms(8, minute, 0)),
+                        PinValue::Analog(percent_to_analog(expected))
+                    );
+                }
+                let config = create_settings("");
+                let temp_decider = TemperatureStateDecider::new(&repository, &config);
+                let reloaded = PidStrategy::new(&repository, &temp_decider, &config);
+                assert_eq!(
+                    reloaded.get_expected_value(&current_temperature, &zone, &Local.ymd(2019, 8, 1).and_hms(8, 20, 0)),
+                    PinValue::Analog(percent_to_analog(75))
+                );
+            }
+
+            it "should re-evaluate value while zone is on"
+            {
+                let on_state = PinState::new(2, PinValue::Analog(percent_to_analog(30)), Local.ymd(2019, 8, 1).and_hms(8, 0, 0), None);
+                assert_eq!(strategy.get_value_to_change_to(&on_state, &zone, &current_temperature, &Local.ymd(2019, 8, 1).and_hms(8, 0, 0)), None);
+                assert_eq!(
+                    strategy.get_value_to_change_to(&on_state, &zone, &current_temperature, &Local.ymd(2019, 8, 1).and_hms(8, 10, 0)),
+                    Some(PinValue::Analog(percent_to_analog(50)))
+                );
+                assert_eq!(
+                    strategy.get_value_to_change_to(&on_state, &zone, &Temperature::new(20.0), &Local.ymd(2019, 8, 1).and_hms(8, 20, 0)),
+                    Some(PinValue::Analog(0))
+                );
+                assert_eq!(
+                    strategy.get_value_to_change_to(&on_state, &zone, &current_temperature, &Local.ymd(2019, 8, 1).and_hms(8, 30, 0)),
+                    None
+                );
+            }
+        }
+    }
+}
@@ -1,9 +1,10 @@
-use chrono::{NaiveTime};
+use chrono::{NaiveTime, NaiveDateTime, Weekday, Datelike, Duration};
 use std::vec::Vec;
 use yaml_rust::{Yaml};
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Deserializer};
 use derive_new::{new};
 use arduino_mqtt_pin::pin::Temperature;
+use crate::config::Settings;
 
 #[derive(Debug, new, Serialize, Deserialize)]
 pub struct Interval
@@ -13,7 +14,104 @@ pub struct Interval
     #[serde(with = "serde_naive_time")]
     end: NaiveTime,
     #[serde(with = "serde_temperature")]
-    expected_temperature: Temperature
+    expected_temperature: Temperature,
+    #[new(default)]
+    #[serde(default, with = "serde_weekdays", skip_serializing_if = "Vec::is_empty")]
+    days: Vec<Weekday>
+}
+
+impl Interval
+{
+    // no days specified means every day
+    pub fn applies_on(&self, weekday: Weekday) -> bool
+    {
+        self.days.is_empty() || self.days.contains(&weekday)
+    }
+
+    // intervals with end before start wrap past midnight e.g. 22:00 - 06:00
+    pub fn matches(&self, now: &NaiveDateTime) -> bool
+    {
+        let time = now.time();
+        if self.start <= self.end {
+            self.applies_on(now.weekday()) && time >= self.start && time < self.end
+        } else if time >= self.start {
+            self.applies_on(now.weekday())
+        } else {
+            // started the day before
+            time < self.end && self.applies_on(now.weekday().pred())
+        }
+    }
+}
+
+// addresses are compared without separators and case
+pub fn rom_sensor_name(node: &str, address: &str) -> String
+{
+    let address: String = address.chars().filter(|c| c.is_ascii_hexdigit()).collect();
+    format!("{}:{}", node, address.to_lowercase())
+}
+
+// yaml keeps 2 and 2.0 apart, both are fine where a fraction is expected
+fn yaml_f32(value: &Yaml) -> Option<f32>
+{
+    value.as_f64().or_else(|| value.as_i64().map(|v| v as f64)).map(|v| v as f32)
+}
+
+// whole number from 0 to max, none when the field is missing
+fn yaml_uint(yaml: &Yaml, field: &str, max: u16, name: &str) -> Result<Option<u16>, String>
+{
+    match &yaml[field] {
+        Yaml::BadValue => Ok(None),
+        value => value.as_i64()
+            .filter(|v| *v >= 0 && *v <= max as i64)
+            .map(|v| Some(v as u16))
+            .ok_or(format!("Zone yaml invalid {} {:?} in {}, expected 0 to {}", field, value, name, max))
+    }
+}
+
+// accepts "Mon,Wed,Fri", "Mon-Fri", "workdays", "weekend", "all"
+pub fn parse_weekdays(days: &str) -> Result<Vec<Weekday>, String>
+{
+    let mut result: Vec<Weekday> = Vec::new();
+    for part in days.split(',').map(|p| p.trim().to_lowercase()).filter(|p| p.len() > 0) {
+        let range: Vec<Weekday> = match part.as_str() {
+            "all" | "everyday" => return Ok(Vec::new()),
+            "workdays" | "weekdays" => vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri],
+            "weekend" | "weekends" => vec![Weekday::Sat, Weekday::Sun],
+            _ => {
+                let mut bounds = part.splitn(2, '-')
+                    .map(|d| d.trim().parse::<Weekday>().map_err(|_| format!("Invalid weekday {}", d)));
+                let from = bounds.next().ok_or(format!("Invalid weekday {}", part))??;
+                let to = bounds.next().unwrap_or(Ok(from))?;
+                let mut range = vec![from];
+                let mut day = from;
+                while day != to {
+                    day = day.succ();
+                    range.push(day);
+                }
+                range
+            }
+        };
+        for day in range {
+            if !result.contains(&day) {
+                result.push(day);
+            }
+        }
+    }
+    Ok(result)
+}
+
+pub(crate) mod serde_weekdays {
+    use super::*;
+    use serde::{Serializer, Deserializer, de::Error};
+
+    pub fn serialize<S: Serializer>(days: &Vec<Weekday>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&days.iter().map(|d| format!("{:?}", d)).collect::<Vec<String>>().join(","))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Weekday>, D::Error> {
+        let days: String = Deserialize::deserialize(deserializer)?;
+        Ok(parse_weekdays(&days).map_err(|e| D::Error::custom(e))?)
+    }
 }
 
 mod serde_temperature {
@@ -34,7 +132,26 @@ mod serde_temperature {
     }
 }
 
-mod serde_naive_time {
+mod serde_option_temperature {
+    use super::*;
+    use serde::{Serializer, Deserializer};
+
+    pub fn serialize<S: Serializer>(x: &Option<Temperature>, s: S) -> Result<S::Ok, S::Error>
+    {
+        match x {
+            Some(t) => s.serialize_some(&t.value),
+            None => s.serialize_none()
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Temperature>, D::Error>
+    {
+        let temp: Option<f32> = Deserialize::deserialize(deserializer)?;
+        Ok(temp.map(|t| Temperature::new(t)))
+    }
+}
+
+pub(crate) mod serde_naive_time {
     use super::*;
     use serde::{Serializer, Deserializer, de::Error};
 
@@ -48,6 +165,102 @@ mod serde_naive_time {
     }
 }
 
+pub(crate) mod serde_option_naive_time {
+    use super::*;
+    use serde::{Serializer, Deserializer, de::Error};
+
+    pub fn serialize<S: Serializer>(time: &Option<NaiveTime>, serializer: S) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(t) => serializer.serialize_some(&format!("{}", t.format("%H:%M"))),
+            None => serializer.serialize_none()
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<NaiveTime>, D::Error> {
+        let time: Option<String> = Deserialize::deserialize(deserializer)?;
+        match time {
+            Some(t) => Ok(Some(NaiveTime::parse_from_str(&t, "%H:%M").map_err(|_| D::Error::custom("failed to parse naive time"))?)),
+            None => Ok(None)
+        }
+    }
+}
+
+// how zone valve output is decided
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy
+{
+    // fully open or closed
+    Hysteresis,
+    // pwm output linear to temperature difference
+    Proportional,
+    // pwm output continuously adjusted by pid controller
+    Pid,
+    // fully open for a part of each cycle linear to temperature difference
+    TimeProportional
+}
+
+// zone valve output while its sensor is stale
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "mode")]
+pub enum FailSafe
+{
+    // closed
+    Off,
+    // kept open at power percent
+    Pwm {
+        #[serde(deserialize_with = "deserialize_percent")]
+        power: u8
+    },
+    // fully open for on_time secs of every cycle secs
+    DutyCycle { on_time: u16, cycle: u16 }
+}
+
+fn deserialize_percent<'de, D>(deserializer: D) -> Result<u8, D::Error> where D: Deserializer<'de>
+{
+    let value = u8::deserialize(deserializer)?;
+    if value > 100 {
+        return Err(serde::de::Error::custom(format!("percent {} is above 100", value)));
+    }
+    Ok(value)
+}
+
+// gains for pwm output in percent. error in celsius, integral in celsius * minutes, derivative in celsius / minute
+#[derive(Debug, new, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PidSettings
+{
+    pub kp: f32,
+    #[serde(default)]
+    pub ki: f32,
+    #[serde(default)]
+    pub kd: f32
+}
+
+// hot water tank charged by turning control_pin on while the heater runs
+#[derive(Debug, new, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HotWaterZone
+{
+    // node reporting the tank temperature
+    pub sensor_name: String,
+    pub sensor_pin: u8,
+    pub control_pin: u8,
+    #[serde(with = "serde_temperature")]
+    pub target_temperature: Temperature,
+    // charging starts when the tank is this much below target
+    #[new(value = "5.0")]
+    #[serde(default = "default_hot_water_hysteresis")]
+    pub hysteresis: f32,
+    // zones do not open while the tank is charging
+    #[new(default)]
+    #[serde(default)]
+    pub priority: bool
+}
+
+fn default_hot_water_hysteresis() -> f32
+{
+    5.0
+}
+
 #[derive(Debug, new, Serialize, Deserialize)]
 pub struct Zone
 {
@@ -55,16 +268,63 @@ pub struct Zone
     pub name: String,
     #[serde(default)]
     pub sensor_pin: u8,
+    // node the sensor reports from, zone name when not set
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensor_node: Option<String>,
+    // DS18B20 ROM address, sensor_pin is not used when set
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensor_address: Option<String>,
     times: Vec<Interval>,
-    pub control_pin: u8
-
+    pub control_pin: u8,
+    // expected temperature when no interval matches
+    #[new(default)]
+    #[serde(default, with = "serde_option_temperature", skip_serializing_if = "Option::is_none")]
+    pub setback_temperature: Option<Temperature>,
+    // continuously adjust pwm output while the zone is on
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<PidSettings>,
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<Strategy>,
+    // higher priority zones open first when max_open_zones is reached
+    #[new(default)]
+    #[serde(default)]
+    pub priority: u8,
+    // general fail_safe when not set
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fail_safe: Option<FailSafe>,
+    // tuning, general settings are used when not set
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature_drop_wait: Option<f32>,
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_pwm_state: Option<u8>,
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_temperature_diff_for_pwm: Option<f32>,
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_proportional_cycle: Option<u16>,
+    // secs of readings averaged into the current temperature
+    #[new(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature_average_time: Option<u16>
 }
 
 impl Zone
 {
     pub fn from_yaml(name: &str, yaml: &Yaml) -> Result<Zone, String>
     {
-        let sensor_pin = yaml["sensor_pin"].as_i64().ok_or("Zone yaml invalid sensor_pin")? as u8;
+        let sensor_pin = match (yaml["sensor_pin"].as_i64(), yaml["sensor_address"].as_str()) {
+            (Some(pin), _) => pin as u8,
+            (None, Some(_)) => 0,
+            (None, None) => return Err("Zone yaml invalid sensor_pin".to_owned())
+        };
         let control_pin = yaml["control_pin"].as_i64().ok_or(format!("Zone yaml invalid control_pin {:?}", yaml["control_pin"]))? as u8;
         let mut v = Vec::new();
         for time in yaml["times"].as_vec().ok_or(format!("Zone yaml invalid times {}", name))? {
@@ -76,19 +336,112 @@ impl Zone
                 &format!("{}:00", time["end"].as_str().ok_or(format!("Zone yaml invalid times.end in {}", name))?),
                 "%H:%M:%S"
             ).map_err(|_| format!("Zone yaml invalid time format times.end in {}", name))?;
+            let days = match time["days"].as_str() {
+                Some(days) => parse_weekdays(days).map_err(|e| format!("Zone yaml invalid times.days in {} {}", name, e))?,
+                None => Vec::new()
+            };
             v.push(Interval {
                 start,
                 end,
-                expected_temperature: Temperature::from_yaml(&time["expected_temperature"]).ok_or(format!("Zone yaml invalid expected_temperature in {}", name))?
+                expected_temperature: Temperature::from_yaml(&time["expected_temperature"]).ok_or(format!("Zone yaml invalid expected_temperature in {}", name))?,
+                days
             });
         }
-        Ok(Zone {name: name.to_string(), sensor_pin, control_pin, times: v})
+        let mut zone = Zone::new(name.to_string(), sensor_pin, v, control_pin);
+        zone.setback_temperature = Temperature::from_yaml(&yaml["setback_temperature"]);
+        zone.sensor_node = yaml["sensor_node"].as_str().map(|node| node.to_owned());
+        zone.sensor_address = yaml["sensor_address"].as_str().map(|address| address.to_owned());
+        zone.strategy = match yaml["strategy"].as_str() {
+            Some("hysteresis") => Some(Strategy::Hysteresis),
+            Some("proportional") => Some(Strategy::Proportional),
+            Some("pid") => Some(Strategy::Pid),
+            Some("time_proportional") => Some(Strategy::TimeProportional),
+            Some(strategy) => return Err(format!("Zone yaml invalid strategy {} in {}", strategy, name)),
+            None => None
+        };
+        zone.priority = yaml_uint(yaml, "priority", u8::max_value() as u16, name)?.unwrap_or(0) as u8;
+        zone.temperature_drop_wait = yaml_f32(&yaml["temperature_drop_wait"]);
+        zone.min_pwm_state = yaml_uint(yaml, "min_pwm_state", 100, name)?.map(|v| v as u8);
+        zone.min_temperature_diff_for_pwm = yaml_f32(&yaml["min_temperature_diff_for_pwm"]);
+        zone.time_proportional_cycle = yaml_uint(yaml, "time_proportional_cycle", u16::max_value(), name)?;
+        zone.temperature_average_time = yaml_uint(yaml, "temperature_average_time", u16::max_value(), name)?;
+        zone.fail_safe = match yaml["fail_safe"]["mode"].as_str() {
+            Some("off") => Some(FailSafe::Off),
+            Some("pwm") => Some(FailSafe::Pwm {
+                power: yaml_uint(&yaml["fail_safe"], "power", 100, name)?.ok_or(format!("Zone yaml missing fail_safe.power in {}", name))? as u8
+            }),
+            Some("duty_cycle") => Some(FailSafe::DutyCycle {
+                on_time: yaml_uint(&yaml["fail_safe"], "on_time", u16::max_value(), name)?.ok_or(format!("Zone yaml missing fail_safe.on_time in {}", name))?,
+                cycle: yaml_uint(&yaml["fail_safe"], "cycle", u16::max_value(), name)?.ok_or(format!("Zone yaml missing fail_safe.cycle in {}", name))?
+            }),
+            Some(mode) => return Err(format!("Zone yaml invalid fail_safe mode {} in {}", mode, name)),
+            None => None
+        };
+        if let Some(kp) = yaml["pid"]["kp"].as_f64() {
+            zone.pid = Some(PidSettings::new(
+                kp as f32,
+                yaml["pid"]["ki"].as_f64().unwrap_or(0.0) as f32,
+                yaml["pid"]["kd"].as_f64().unwrap_or(0.0) as f32
+            ));
+        }
+        Ok(zone)
     }
 
-    pub fn get_expected_temperature(&self, now: &NaiveTime) -> Option<Temperature>
+    // readings are saved by node and pin, ROM addressed ones by "node:address" and pin 0
+    pub fn get_sensor_name(&self) -> String
+    {
+        let node = self.sensor_node.as_ref().unwrap_or(&self.name);
+        match &self.sensor_address {
+            Some(address) => rom_sensor_name(node, address),
+            None => node.clone()
+        }
+    }
+
+    pub fn get_sensor_pin(&self) -> u8
+    {
+        if self.sensor_address.is_some() { 0 } else { self.sensor_pin }
+    }
+
+    pub fn get_temperature_drop_wait(&self, config: &Settings) -> f32
+    {
+        self.temperature_drop_wait.unwrap_or_else(|| config.temperature_drop_wait())
+    }
+
+    pub fn get_min_pwm_state(&self, config: &Settings) -> u8
+    {
+        self.min_pwm_state.unwrap_or_else(|| config.min_pwm_state())
+    }
+
+    pub fn get_min_temperature_diff_for_pwm(&self, config: &Settings) -> f32
+    {
+        self.min_temperature_diff_for_pwm.unwrap_or_else(|| config.min_temperature_diff_for_pwm())
+    }
+
+    pub fn get_time_proportional_cycle(&self, config: &Settings) -> u16
+    {
+        self.time_proportional_cycle.unwrap_or_else(|| config.time_proportional_cycle())
+    }
+
+    // current temperature is the average of readings within this time
+    pub fn get_temperature_average_time(&self, config: &Settings) -> Duration
+    {
+        Duration::seconds(self.temperature_average_time.unwrap_or_else(|| config.temperature_average_time()) as i64)
+    }
+
+    // zones with pid settings default to pid strategy
+    pub fn get_strategy(&self) -> Strategy
+    {
+        match (self.strategy, &self.pid) {
+            (Some(strategy), _) => strategy,
+            (None, Some(_)) => Strategy::Pid,
+            (None, None) => Strategy::Proportional
+        }
+    }
+
+    pub fn get_expected_temperature(&self, now: &NaiveDateTime) -> Option<Temperature>
     {
         for time in &self.times {
-          if *now >= time.start && *now < time.end {
+          if time.matches(now) {
               return Some(time.expected_temperature.clone());
           }
         }
@@ -102,6 +455,9 @@ mod tests {
 
     use speculate::speculate;
     use super::*;
+    use chrono::NaiveDate;
+    use yaml_rust::YamlLoader;
+    use crate::config::tests::create_settings;
 
     fn times_expected_data() -> Vec<(&'static str, NaiveTime, f32)>
     {
@@ -127,16 +483,245 @@ mod tests {
             it "should have temperature"
             {
                 for (e, time, temp) in times_expected_data() {
-                    assert_eq!(zone.get_expected_temperature(&time).expect(e), Temperature::new(temp));
+                    assert_eq!(zone.get_expected_temperature(&NaiveDate::from_ymd(2019, 8, 1).and_time(time)).expect(e), Temperature::new(temp));
                 }
             }
 
             it "should not have temperature"
             {
                 for hour in vec![9, 20] {
-                    assert!(zone.get_expected_temperature(&NaiveTime::from_hms(hour, 0, 0)).is_none());
+                    assert!(zone.get_expected_temperature(&NaiveDate::from_ymd(2019, 8, 1).and_hms(hour, 0, 0)).is_none());
+                }
+            }
+        }
+
+        describe "zone midnight"
+        {
+            before {
+                let intervals = vec![
+                    Interval::new(NaiveTime::from_hms(22, 0, 0), NaiveTime::from_hms(6, 0, 0), Temperature::new(18.0)),
+                    Interval::new(NaiveTime::from_hms(12, 0, 0), NaiveTime::from_hms(0, 0, 0), Temperature::new(21.0))
+                ];
+                let zone = Zone::new(String::from("zone1"), 1, intervals, 2);
+                let weekend_intervals = vec![
+                    Interval { days: parse_weekdays("Fri,Sat").unwrap(), ..Interval::new(NaiveTime::from_hms(23, 0, 0), NaiveTime::from_hms(2, 0, 0), Temperature::new(22.0)) }
+                ];
+                let weekend_zone = Zone::new(String::from("zone2"), 1, weekend_intervals, 3);
+            }
+
+            it "should match intervals crossing midnight"
+            {
+                for (hour, minute, second, expected) in vec![
+                    (21, 59, 59, Some(21.0)),
+                    (22, 0, 0, Some(18.0)),
+                    (23, 59, 0, Some(18.0)),
+                    (23, 59, 59, Some(18.0)),
+                    (0, 0, 0, Some(18.0)),
+                    (5, 59, 59, Some(18.0)),
+                    (6, 0, 0, None),
+                    (11, 59, 59, None),
+                    (12, 0, 0, Some(21.0)),
+                ] {
+                    assert_eq!(
+                        zone.get_expected_temperature(&NaiveDate::from_ymd(2019, 8, 1).and_hms(hour, minute, second)),
+                        expected.map(|t| Temperature::new(t)),
+                        "time {}:{}:{}", hour, minute, second
+                    );
+                }
+            }
+
+            it "should end at midnight"
+            {
+                let zone = Zone::new(
+                    String::from("zone3"),
+                    1,
+                    vec![Interval::new(NaiveTime::from_hms(20, 0, 0), NaiveTime::from_hms(0, 0, 0), Temperature::new(21.0))],
+                    4
+                );
+                assert!(zone.get_expected_temperature(&NaiveDate::from_ymd(2019, 8, 1).and_hms(23, 59, 59)).is_some());
+                assert!(zone.get_expected_temperature(&NaiveDate::from_ymd(2019, 8, 2).and_hms(0, 0, 0)).is_none());
+            }
+
+            it "should use the day interval started on"
+            {
+                // 2019-08-09 is friday
+                for (day, hour, minute, expected) in vec![
+                    (8, 23, 59, false),
+                    (9, 0, 0, false),
+                    (9, 23, 59, true),
+                    (10, 0, 0, true),
+                    (10, 1, 59, true),
+                    (10, 23, 0, true),
+                    (11, 0, 0, true),
+                    (11, 23, 0, false),
+                    (12, 0, 0, false),
+                ] {
+                    assert_eq!(
+                        weekend_zone.get_expected_temperature(&NaiveDate::from_ymd(2019, 8, day).and_hms(hour, minute, 0)).is_some(),
+                        expected,
+                        "day {} time {}:{}", day, hour, minute
+                    );
+                }
+            }
+        }
+
+        describe "zone strategy"
+        {
+            it "should select strategy"
+            {
+                let mut zone = Zone::new(String::from("zone1"), 1, vec![], 2);
+                assert_eq!(zone.get_strategy(), Strategy::Proportional);
+                zone.pid = Some(PidSettings::new(50.0, 0.5, 0.0));
+                assert_eq!(zone.get_strategy(), Strategy::Pid);
+                zone.strategy = Some(Strategy::TimeProportional);
+                assert_eq!(zone.get_strategy(), Strategy::TimeProportional);
+            }
+
+            it "should deserialize strategy names"
+            {
+                for (name, expected) in vec![
+                    ("hysteresis", Strategy::Hysteresis),
+                    ("proportional", Strategy::Proportional),
+                    ("pid", Strategy::Pid),
+                    ("time_proportional", Strategy::TimeProportional),
+                ] {
+                    let zone: Zone = serde_yaml::from_str(&format!("times: []\ncontrol_pin: 3\nstrategy: {}", name)).unwrap();
+                    assert_eq!(zone.get_strategy(), expected);
+                }
+                assert!(serde_yaml::from_str::<Zone>("times: []\ncontrol_pin: 3\nstrategy: unknown").is_err());
+            }
+
+            it "should fall back to general tuning"
+            {
+                let config = create_settings("temperature_average_time: 900");
+                let mut zone = Zone::new(String::from("zone1"), 1, vec![], 2);
+                assert_eq!(zone.get_temperature_drop_wait(&config), config.temperature_drop_wait());
+                assert_eq!(zone.get_min_pwm_state(&config), config.min_pwm_state());
+                assert_eq!(zone.get_temperature_average_time(&config), Duration::minutes(15));
+
+                let yaml = "times: []\nsensor_pin: 1\ncontrol_pin: 3\ntemperature_drop_wait: 0.2\nmin_pwm_state: 45\nmin_temperature_diff_for_pwm: 0.5\ntime_proportional_cycle: 600\ntemperature_average_time: 300";
+                zone = serde_yaml::from_str(yaml).unwrap();
+                let zone_from_yaml = Zone::from_yaml("zone1", &YamlLoader::load_from_str(yaml).unwrap()[0]).unwrap();
+                for zone in vec![zone, zone_from_yaml] {
+                    assert_eq!(zone.get_temperature_drop_wait(&config), 0.2);
+                    assert_eq!(zone.get_min_pwm_state(&config), 45);
+                    assert_eq!(zone.get_min_temperature_diff_for_pwm(&config), 0.5);
+                    assert_eq!(zone.get_time_proportional_cycle(&config), 600);
+                    assert_eq!(zone.get_temperature_average_time(&config), Duration::minutes(5));
+                }
+            }
+
+            it "should read whole numbers as fractions"
+            {
+                let config = create_settings("");
+                let yaml = "times: []\nsensor_pin: 1\ncontrol_pin: 3\ntemperature_drop_wait: 2\nmin_temperature_diff_for_pwm: 1";
+                let zone = Zone::from_yaml("zone1", &YamlLoader::load_from_str(yaml).unwrap()[0]).unwrap();
+                assert_eq!(zone.get_temperature_drop_wait(&config), 2.0);
+                assert_eq!(zone.get_min_temperature_diff_for_pwm(&config), 1.0);
+                let yaml = "times: []\nsensor_pin: 1\ncontrol_pin: 3\nmin_pwm_state: 150";
+                assert!(Zone::from_yaml("zone1", &YamlLoader::load_from_str(yaml).unwrap()[0]).is_err());
+            }
+
+            it "should deserialize fail safe modes"
+            {
+                for (fail_safe, expected) in vec![
+                    ("{mode: off}", FailSafe::Off),
+                    ("{mode: pwm, power: 40}", FailSafe::Pwm { power: 40 }),
+                    ("{mode: duty_cycle, on_time: 600, cycle: 1800}", FailSafe::DutyCycle { on_time: 600, cycle: 1800 }),
+                ] {
+                    let yaml = format!("times: []\ncontrol_pin: 3\nfail_safe: {}", fail_safe);
+                    let zone: Zone = serde_yaml::from_str(&yaml).unwrap();
+                    assert_eq!(zone.fail_safe, Some(expected));
+                    let zone_from_yaml = Zone::from_yaml("zone1", &YamlLoader::load_from_str(&format!("{}\nsensor_pin: 1", yaml)).unwrap()[0]).unwrap();
+                    assert_eq!(zone_from_yaml.fail_safe, Some(expected));
+                }
+                assert!(serde_yaml::from_str::<Zone>("times: []\ncontrol_pin: 3").unwrap().fail_safe.is_none());
+            }
+
+            it "should check priority range"
+            {
+                let yaml = "times: []\nsensor_pin: 1\ncontrol_pin: 3\npriority: 2";
+                assert_eq!(Zone::from_yaml("bedroom", &YamlLoader::load_from_str(yaml).unwrap()[0]).unwrap().priority, 2);
+                for priority in vec!["256", "-1"] {
+                    let yaml = format!("times: []\nsensor_pin: 1\ncontrol_pin: 3\npriority: {}", priority);
+                    let error = Zone::from_yaml("bedroom", &YamlLoader::load_from_str(&yaml).unwrap()[0]).unwrap_err();
+                    assert!(error.contains("priority") && error.contains("bedroom"), "{}", error);
+                }
+            }
+
+            it "should reject fail safe power out of range"
+            {
+                for power in vec!["300", "101", "-1", "half"] {
+                    let yaml = format!("times: []\nsensor_pin: 1\ncontrol_pin: 3\nfail_safe: {{mode: pwm, power: {}}}", power);
+                    let error = Zone::from_yaml("bedroom", &YamlLoader::load_from_str(&yaml).unwrap()[0]).unwrap_err();
+                    assert!(error.contains("power") && error.contains("bedroom"), "{}", error);
+                    assert!(serde_yaml::from_str::<Zone>(&yaml).is_err(), "{}", power);
+                }
+            }
+        }
+
+        describe "zone weekdays"
+        {
+            before {
+                let intervals = vec![
+                    Interval { days: parse_weekdays("workdays").unwrap(), ..Interval::new(NaiveTime::from_hms(6, 0, 0), NaiveTime::from_hms(8, 0, 0), Temperature::new(21.0)) },
+                    Interval { days: parse_weekdays("weekend").unwrap(), ..Interval::new(NaiveTime::from_hms(8, 0, 0), NaiveTime::from_hms(11, 0, 0), Temperature::new(22.0)) },
+                    Interval { days: parse_weekdays("Mon,Wed,Fri").unwrap(), ..Interval::new(NaiveTime::from_hms(18, 0, 0), NaiveTime::from_hms(20, 0, 0), Temperature::new(20.5)) },
+                    Interval::new(NaiveTime::from_hms(21, 0, 0), NaiveTime::from_hms(22, 0, 0), Temperature::new(19.0))
+                ];
+                let zone = Zone::new(String::from("zone1"), 1, intervals, 2);
+            }
+
+            it "should have temperature on matching days"
+            {
+                // 2019-08-05 is monday
+                for (day, hour, expected) in vec![
+                    (5, 7, Some(21.0)),
+                    (9, 7, Some(21.0)),
+                    (10, 7, None),
+                    (10, 9, Some(22.0)),
+                    (11, 9, Some(22.0)),
+                    (5, 9, None),
+                    (5, 19, Some(20.5)),
+                    (6, 19, None),
+                    (7, 19, Some(20.5)),
+                    (9, 19, Some(20.5)),
+                    (11, 19, None),
+                    (6, 21, Some(19.0)),
+                    (11, 21, Some(19.0)),
+                ] {
+                    assert_eq!(
+                        zone.get_expected_temperature(&NaiveDate::from_ymd(2019, 8, day).and_hms(hour, 0, 0)),
+                        expected.map(|t| Temperature::new(t)),
+                        "day {} hour {}", day, hour
+                    );
                 }
             }
+
+            it "should parse weekdays"
+            {
+                assert_eq!(parse_weekdays("Mon,Wed,Fri").unwrap(), vec![Weekday::Mon, Weekday::Wed, Weekday::Fri]);
+                assert_eq!(parse_weekdays("mon-thu, sun").unwrap(), vec![Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Sun]);
+                assert_eq!(parse_weekdays("Sat-Mon").unwrap(), vec![Weekday::Sat, Weekday::Sun, Weekday::Mon]);
+                assert_eq!(parse_weekdays("weekend").unwrap(), vec![Weekday::Sat, Weekday::Sun]);
+                assert!(parse_weekdays("all").unwrap().is_empty());
+                assert!(parse_weekdays("").unwrap().is_empty());
+                assert!(parse_weekdays("Mon,Someday").is_err());
+            }
+
+            it "should serialize days"
+            {
+                let yaml = serde_yaml::to_string(&zone).unwrap();
+                let zone_from_yaml: Zone = serde_yaml::from_str(&yaml).unwrap();
+                assert_eq!(zone_from_yaml.times[0].days, parse_weekdays("workdays").unwrap());
+                assert_eq!(zone_from_yaml.times[2].days, vec![Weekday::Mon, Weekday::Wed, Weekday::Fri]);
+                assert!(zone_from_yaml.times[3].days.is_empty());
+
+                let json = serde_json::to_string(&zone).unwrap();
+                assert!(json.contains("\"days\":\"Mon,Wed,Fri\""), "{}", json);
+                let zone_from_json: Zone = serde_json::from_str(&json).unwrap();
+                assert_eq!(zone_from_json.times[1].days, vec![Weekday::Sat, Weekday::Sun]);
+            }
         }
     }
 }
\ No newline at end of file